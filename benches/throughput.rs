@@ -0,0 +1,231 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_decimal::Decimal;
+use transactions::client::ClientId;
+use transactions::id::num_id;
+use transactions::io::TransactionReader;
+use transactions::store::Store;
+use transactions::transaction::{Transaction, TransactionId, TransactionKind};
+
+/// Row counts every scenario is measured at
+const SIZES: [usize; 2] = [1_000_000, 10_000_000];
+
+/// Environment variable overriding `SIZES` with a comma separated list, handy for quick local runs
+const SIZES_VAR: &str = "TRANSACTIONS_BENCH_ROWS";
+
+/// Number of distinct client accounts spread across the generated rows
+const CLIENTS: u64 = 10_000;
+
+
+/// Allocator wrapper keeping track of live and peak heap usage so memory can be reported next to timings
+struct TrackingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: TrackingAllocator = TrackingAllocator;
+
+/// Run a closure over the given number of items and report how many bytes of heap it needed on top of what was already
+/// allocated
+fn report_memory<T>(scenario: &str, rows: usize, items: usize, f: impl FnOnce() -> T) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let result = f();
+    let retained = ALLOCATED.load(Ordering::Relaxed).saturating_sub(before);
+    let peak = PEAK.load(Ordering::Relaxed).saturating_sub(before);
+    drop(result);
+    println!("memory/{}/{}: peak {} bytes, retained {} bytes ({:.1} bytes/item)",
+            scenario, rows, peak, retained, retained as f64 / items as f64);
+}
+
+
+/// Row counts to benchmark, taken from `SIZES_VAR` when set
+fn sizes() -> Vec<usize> {
+    match std::env::var(SIZES_VAR) {
+        Ok(sizes) => sizes.split(',')
+            .map(|rows| rows.trim().parse().unwrap_or_else(|_| panic!("Invalid row count in {}: {}", SIZES_VAR, rows)))
+            .collect(),
+        Err(_) => SIZES.to_vec(),
+    }
+}
+
+/// Cheap deterministic pseudo-random sequence so runs are comparable without pulling in a RNG crate
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+}
+
+/// Build a realistic mix of rows: mostly deposits and withdrawals with a sprinkling of dispute flows
+fn generate_transactions(rows: usize) -> Vec<Transaction> {
+    let mut rng = Lcg(rows as u64);
+    let mut transactions = Vec::with_capacity(rows);
+    let mut next_id: u64 = 1;
+    while transactions.len() < rows {
+        let client = num_id(rng.next() % CLIENTS + 1);
+        let amount = Decimal::new((rng.next() % 1_000_000) as i64, 4);
+        let id = num_id(next_id);
+        next_id += 1;
+        match rng.next() % 100 {
+            0..=59 => transactions.push(Transaction::new(TransactionKind::Deposit, client, id, amount)),
+            60..=94 => transactions.push(Transaction::new(TransactionKind::Withdrawal, client, id, amount)),
+            roll => {
+                // Deposit followed by a dispute and its conclusion
+                transactions.push(Transaction::new(TransactionKind::Deposit, client, id, amount));
+                transactions.push(Transaction::new(TransactionKind::Dispute, client, id, Decimal::ZERO));
+                let kind = if roll == 99 { TransactionKind::Chargeback } else { TransactionKind::Resolve };
+                transactions.push(Transaction::new(kind, client, id, Decimal::ZERO));
+            }
+        }
+    }
+    transactions.truncate(rows);
+    transactions
+}
+
+/// Write the generated rows to a CSV in the temp directory, reusing it if an earlier run already created it
+fn generate_csv(rows: usize) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("transactions-bench-{}.csv", rows));
+    if path.exists() { return path; }
+
+    let mut out = BufWriter::new(File::create(&path).expect("Couldn't create benchmark input"));
+    writeln!(out, "type,client,tx,amount").unwrap();
+    for transaction in generate_transactions(rows) {
+        writeln!(out, "{},{},{},{}", transaction.kind().name(), transaction.client_id(), transaction.id(), transaction.amount()).unwrap();
+    }
+    out.flush().unwrap();
+    path
+}
+
+
+/// Execute every transaction against a fresh store
+fn execute(transactions: &[Transaction]) -> Store {
+    let mut store = Store::new();
+    for transaction in transactions {
+        let mut transaction = *transaction;
+        let _ = transaction.exec(&mut store);
+    }
+    store
+}
+
+/// Look up every transaction and its client in a populated store, the lookups are what the `store` scenario measures
+fn lookup(store: &mut Store, lookups: &[(TransactionId, ClientId)]) {
+    for (id, client) in lookups {
+        let _ = black_box(store.get_transaction(*id));
+        black_box(store.get_or_create_client(*client));
+    }
+}
+
+/// Parse, execute and serialise the client table, just like `main` does
+fn end_to_end(path: &str) -> Store {
+    let mut store = Store::new();
+    for transaction in TransactionReader::new(path).unwrap() {
+        let mut transaction = transaction.unwrap();
+        let _ = transaction.exec(&mut store);
+    }
+    store.write_clients(&mut std::io::sink()).unwrap();
+    store
+}
+
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    group.sample_size(10);
+    for rows in sizes() {
+        let path = generate_csv(rows);
+        let path = path.to_str().unwrap();
+        report_memory("parse", rows, rows, || TransactionReader::new(path).unwrap().collect::<Vec<_>>());
+
+        group.throughput(Throughput::Elements(rows as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(rows), path, |b, path| {
+            b.iter(|| TransactionReader::new(path).unwrap().for_each(|t| { black_box(t.unwrap()); }))
+        });
+    }
+    group.finish();
+}
+
+fn exec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("exec");
+    group.sample_size(10);
+    for rows in sizes() {
+        let transactions = generate_transactions(rows);
+        report_memory("exec", rows, rows, || execute(&transactions));
+
+        group.throughput(Throughput::Elements(rows as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(rows), &transactions, |b, transactions| {
+            b.iter(|| execute(transactions))
+        });
+    }
+    group.finish();
+}
+
+fn store_lookups(c: &mut Criterion) {
+    let mut group = c.benchmark_group("store");
+    group.sample_size(10);
+    for rows in sizes() {
+        let transactions = generate_transactions(rows);
+        let mut store = execute(&transactions);
+
+        let mut rng = Lcg(42);
+        // IDs are built up front so string IDs don't add formatting to the lookups being measured
+        let lookups: Vec<(TransactionId, ClientId)> = (0..100_000)
+            .map(|_| rng.next() % rows as u64 + 1)
+            .map(|id| (num_id(id), num_id(id % CLIENTS + 1)))
+            .collect();
+        report_memory("store", rows, lookups.len(), || lookup(&mut store, &lookups));
+
+        group.throughput(Throughput::Elements(lookups.len() as u64));
+        group.bench_function(BenchmarkId::new("get_transaction", rows), |b| {
+            b.iter(|| for (id, _) in &lookups { let _ = black_box(store.get_transaction(*id)); })
+        });
+        group.bench_function(BenchmarkId::new("get_or_create_client", rows), |b| {
+            b.iter(|| for (_, client) in &lookups { black_box(store.get_or_create_client(*client)); })
+        });
+    }
+    group.finish();
+}
+
+fn end_to_end_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("end_to_end");
+    group.sample_size(10);
+    for rows in sizes() {
+        let path = generate_csv(rows);
+        let path = path.to_str().unwrap();
+        report_memory("end_to_end", rows, rows, || end_to_end(path));
+
+        group.throughput(Throughput::Elements(rows as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(rows), path, |b, path| {
+            b.iter(|| end_to_end(path))
+        });
+    }
+    group.finish();
+}
+
+
+criterion_group!(benches, parse, exec, store_lookups, end_to_end_bench);
+criterion_main!(benches);
@@ -0,0 +1,536 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{mpsc, Arc, PoisonError};
+use std::thread;
+use std::time::Instant;
+
+use chrono::Utc;
+use clap::{Args, Parser, Subcommand};
+use rust_decimal::Decimal;
+
+use crate::{
+    client::ClientId, config::{Config, StorageBackend}, diff::{self, Tolerance}, engine::{Engine, Flag, Settled}, error::Error,
+    events::NdjsonWriter, metrics::{self, Metrics}, policy, transaction::{OpeningBalance, Transaction, TransactionError}, store::Store, validate::{self, Thresholds},
+    io::{self, InputFormat, OutputFormat, TransactionReader}, logging::{self, LogFormat},
+};
+
+
+/// Transaction processing engine - reads transactions and outputs the resulting client accounts
+#[derive(Debug, Parser)]
+#[command(name = "transactions", version)]
+pub struct Cli {
+    /// TOML configuration file providing defaults for the options below
+    #[arg(long, global = true, env = "TRANSACTIONS_CONFIG", value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// How much to log to stderr: error, warn, info, debug, trace or a filter such as `transactions::store=trace`
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Append logs to this file instead of stderr
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<String>,
+
+    /// Encoding of the log lines, defaults to the configuration file or text
+    #[arg(long, global = true, value_enum, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Transactions file to process when no subcommand is given (same as `process <INPUT>`)
+    #[arg(value_name = "INPUT")]
+    pub input: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Execute all transactions from the input and output the resulting client accounts
+    Process {
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Dry run the input against a throwaway store and report every problem found, exits with 1 if it is rejected
+    Validate {
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+        /// Number of parse errors, duplicate IDs and unknown references tolerated before rejecting the input
+        #[arg(long, default_value_t = 0, value_name = "COUNT")]
+        max_errors: u64,
+        /// Number of failing transactions tolerated before rejecting the input, unlimited by default
+        #[arg(long, value_name = "COUNT")]
+        max_failures: Option<u64>,
+    },
+    /// Execute transactions from the input on top of the state stored in a snapshot
+    Replay {
+        /// Snapshot to start processing from
+        #[arg(long, value_name = "PATH")]
+        snapshot: String,
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Output the client accounts stored in a snapshot
+    Report {
+        /// Snapshot to report on
+        #[arg(long, value_name = "PATH")]
+        snapshot: String,
+        #[command(flatten)]
+        output: OutputArgs,
+        #[command(flatten)]
+        reports: ReportArgs,
+    },
+    /// Compare the client accounts of two snapshots or account outputs, exits with 1 if they differ
+    Diff {
+        /// Snapshot or accounts CSV/JSON with the expected state
+        left: String,
+        /// Snapshot or accounts CSV/JSON with the state to compare against it
+        right: String,
+        #[command(flatten)]
+        output: OutputArgs,
+        /// Largest difference between two amounts which is ignored
+        #[arg(long, default_value_t = Decimal::ZERO, value_name = "AMOUNT")]
+        tolerance: Decimal,
+        /// Round amounts to this many decimal places before comparing them
+        #[arg(long, value_name = "PLACES")]
+        decimal_places: Option<u32>,
+    },
+    /// List the stored transactions of a single client from a snapshot
+    History {
+        /// Snapshot holding the transactions
+        #[arg(long, value_name = "PATH")]
+        snapshot: String,
+        /// Client to list the transactions of
+        client: ClientId,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+}
+
+/// Options selecting the transactions input
+#[derive(Debug, Args)]
+pub struct InputArgs {
+    /// Transactions file to read, `-` reads from stdin
+    #[arg(value_name = "INPUT")]
+    pub path: String,
+    /// Encoding of the input, defaults to the configuration file or csv
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub input_format: Option<InputFormat>,
+}
+
+/// Options selecting where and how results are written
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    /// File to write results to, defaults to stdout
+    #[arg(long, short, value_name = "PATH")]
+    pub output: Option<String>,
+    /// Encoding of the output, defaults to the configuration file or csv
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub format: Option<OutputFormat>,
+}
+
+
+/// Options for the reports written alongside the client accounts
+#[derive(Debug, Default, Args)]
+pub struct ReportArgs {
+    /// Also write the accounts overdrawn using their credit limit to this file
+    #[arg(long, value_name = "PATH")]
+    pub overdrawn: Option<String>,
+    /// Also write the accounts owing money, through negative available funds or spent disputed funds, to this file
+    #[arg(long, value_name = "PATH")]
+    pub exposure: Option<String>,
+    /// Also write every dispute with its transaction, amount and status to this file
+    #[arg(long, value_name = "PATH")]
+    pub disputes: Option<String>,
+    /// Also write the dispute counts and amounts of each reason code to this file
+    #[arg(long, value_name = "PATH")]
+    pub disputes_by_reason: Option<String>,
+    /// Also write the dispute counts and amounts of each client to this file
+    #[arg(long, value_name = "PATH")]
+    pub disputes_by_client: Option<String>,
+}
+
+/// Options for what a processing run writes besides the client accounts
+#[derive(Debug, Default, Args)]
+pub struct RunArgs {
+    /// Save the resulting store as a snapshot to pick up from with `replay`
+    #[arg(long, value_name = "PATH")]
+    pub save_snapshot: Option<String>,
+    /// Write every account event (deposited, disputed, locked, ...) as NDJSON to this file, `-` for stdout
+    #[arg(long, value_name = "PATH")]
+    pub events: Option<String>,
+    #[command(flatten)]
+    pub reports: ReportArgs,
+    /// Write processing metrics in the Prometheus text format to this file once the input is processed
+    #[arg(long, value_name = "PATH")]
+    pub metrics: Option<String>,
+    /// Serve processing metrics on `/metrics` at this address (such as `127.0.0.1:9100`) while the input is processed
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<String>,
+    /// Write a summary of the run (rows, results, money moved, timing) to this file once the input is processed, `-`
+    /// or no path for stderr, the path has to follow an `=` so the input isn't taken for it
+    #[arg(long, value_name = "PATH", num_args = 0..=1, require_equals = true, default_missing_value = io::STDIO_PATH)]
+    pub summary: Option<String>,
+}
+
+
+/// Rows parsed ahead of the engine when reading on a thread of its own
+const READ_AHEAD_ROWS: usize = 1024;
+
+
+/// Run the command line request to completion
+pub fn run(cli: Cli) -> Result<(), Error> {
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::from_env()?,
+    };
+
+    let mut logging = config.logging.clone();
+    if let Some(level) = cli.log_level { logging.level = level; }
+    if let Some(path) = cli.log_file { logging.file = Some(path); }
+    if let Some(format) = cli.log_format { logging.format = format; }
+    logging::init(&logging)?;
+
+    let command = match (cli.command, cli.input) {
+        (Some(_), Some(path)) => return Err(Error::Usage(format!("unexpected input {} before the subcommand", path))),
+        (Some(command), None) => command,
+        (None, Some(path)) => Command::Process {
+            input: InputArgs { path, input_format: None },
+            output: OutputArgs { output: None, format: None },
+            run: RunArgs::default(),
+        },
+        (None, None) => return Err(Error::Usage("no input given, see `transactions --help`".to_string())),
+    };
+
+    match command {
+        Command::Process { input, output, run } => {
+            let store = execute(prepare(open_store(&config)?, &config)?, &input, &output, &run, &config)?;
+            finish(&store, &output, run.save_snapshot.as_deref(), &run.reports, &config)?;
+            match (config.storage.backend, &config.storage.path) {
+                (StorageBackend::Snapshot, Some(path)) => store.save_snapshot(path),
+                _ => Ok(()),
+            }
+        },
+        Command::Replay { snapshot, input, output, run } => {
+            let store = execute(prepare(Store::load_snapshot(&snapshot)?, &config)?, &input, &output, &run, &config)?;
+            finish(&store, &output, run.save_snapshot.as_deref(), &run.reports, &config)
+        },
+        Command::Validate { input, output, max_errors, max_failures } => {
+            let format = input.input_format.unwrap_or(config.input.format);
+            let reader = TransactionReader::with_format(&input.path, format)?;
+            let report = validate::validate(reader, engine(prepare(open_store(&config)?, &config)?, &config))?;
+            let path = output.output.as_deref();
+            report.write(output.format.unwrap_or(config.output.format), &mut io::open_output(path)?)
+                .map_err(|source| Error::Io { path: path.unwrap_or(io::STDIO_PATH).to_string(), source })?;
+            report.check(Thresholds { max_errors, max_failures })
+        },
+        Command::Report { snapshot, output, reports } => {
+            finish(&Store::load_snapshot(&snapshot)?, &output, None, &reports, &config)
+        },
+        Command::Diff { left, right, output, tolerance, decimal_places } => {
+            let tolerance = Tolerance { absolute: tolerance, decimal_places };
+            let diffs = diff::diff(&diff::load_accounts(&left)?, &diff::load_accounts(&right)?, tolerance);
+            let path = output.output.as_deref();
+            diff::write_diffs(&diffs, output.format.unwrap_or(config.output.format), &mut io::open_output(path)?)
+                .map_err(|source| Error::Io { path: path.unwrap_or(io::STDIO_PATH).to_string(), source })?;
+            match diffs.len() {
+                0 => Ok(()),
+                count => Err(Error::Rejected(format!("{} clients differ between {} and {}", count, left, right))),
+            }
+        },
+        Command::History { snapshot, client, output } => history(&Store::load_snapshot(&snapshot)?, client, &output, &config),
+    }
+}
+
+
+/// Open the store of the configured storage backend, a snapshot which doesn't exist yet starts out empty
+fn open_store(config: &Config) -> Result<Store, Error> {
+    match (config.storage.backend, &config.storage.path) {
+        (StorageBackend::Snapshot, Some(path)) if Path::new(path).exists() => Store::load_snapshot(path),
+        _ => Ok(Store::new()),
+    }
+}
+
+/// Apply the retention policy, credit limits, opening balances and client master data from the configuration to the
+/// store
+fn prepare(mut store: Store, config: &Config) -> Result<Store, Error> {
+    store.set_retention(config.retention);
+    if let Some(path) = &config.accounts.credit_limits {
+        store.set_credit_limits(io::read_credit_limits(path)?);
+    }
+    let profiles = config.accounts.master.as_deref().map(io::read_client_master).transpose()?;
+    // Accounts can only be opened for the clients of the master file in strict mode
+    let listed: Option<HashSet<ClientId>> = profiles.as_ref().filter(|_| config.accounts.strict)
+        .map(|profiles| profiles.iter().map(|(id, _)| *id).collect());
+    if let Some(path) = &config.accounts.opening_balances {
+        let opened_at = Utc::now();
+        for (line, record) in io::read_opening_balances(path)? {
+            if listed.as_ref().is_some_and(|listed| !listed.contains(&record.client)) {
+                return Err(Error::Parse {
+                    path: path.clone(), line, message: format!("client {} isn't in the client master file", record.client),
+                });
+            }
+            let opening = OpeningBalance::new(record.client, record.available, record.held, record.locked, opened_at);
+            if !store.open_account(opening) {
+                return Err(Error::Parse {
+                    path: path.clone(), line, message: format!("client {} already has an account", record.client),
+                });
+            }
+        }
+    }
+    if let Some(profiles) = profiles {
+        store.set_profiles(profiles, config.accounts.strict);
+    }
+    Ok(store)
+}
+
+/// Execute every transaction from the input against the store, optionally writing events, metrics and a summary of
+/// the run as it goes
+fn execute(store: Store, input: &InputArgs, output: &OutputArgs, run: &RunArgs, config: &Config) -> Result<Store, Error> {
+    let started = Instant::now();
+    let format = input.input_format.unwrap_or(config.input.format);
+    let metrics = Metrics::shared(&store);
+    let mut engine = engine(store, config);
+    if run.metrics.is_some() || run.metrics_addr.is_some() || run.summary.is_some() {
+        engine = engine.with_metrics(Arc::clone(&metrics));
+    }
+    if let Some(addr) = &run.metrics_addr {
+        metrics::serve(addr, Arc::clone(&metrics)).map_err(|source| Error::Io { path: addr.clone(), source })?;
+    }
+    let events = run.events.as_deref();
+    if let Some(path) = events {
+        engine.subscribe(Box::new(NdjsonWriter::new(io::open_output(Some(path))?)));
+    }
+
+    let mut rows: Box<dyn Iterator<Item = (u64, Result<Transaction, Error>)>> = if config.engine.read_ahead {
+        Box::new(read_ahead(input.path.clone(), format))
+    } else {
+        let mut reader = TransactionReader::with_format(&input.path, format)?;
+        Box::new(std::iter::from_fn(move || reader.next().map(|row| (reader.line(), row))))
+    };
+    loop {
+        // Every row gets a span so whatever happens to it while it's read, executed and stored can be traced back
+        let row = tracing::info_span!("row", line = tracing::field::Empty).entered();
+        let Some((line, transaction)) = rows.next() else { break };
+        row.record("line", line);
+
+        // Failed transactions are ignored, as they leave the accounts untouched
+        engine.process(transaction?);
+        report_warnings(&mut engine);
+    }
+
+    engine.finish();
+    report_warnings(&mut engine);
+    engine.flush().map_err(|source| Error::Io { path: events.unwrap_or(io::STDIO_PATH).to_string(), source })?;
+
+    if let Some(path) = &run.metrics {
+        let mut out = io::open_output(Some(path))?;
+        metrics.lock().unwrap_or_else(PoisonError::into_inner).write(&mut out).and_then(|_| out.flush())
+            .map_err(|source| Error::Io { path: path.clone(), source })?;
+    }
+    if let Some(path) = &run.summary {
+        let summary = metrics.lock().unwrap_or_else(PoisonError::into_inner).summary(started.elapsed());
+        let mut out: Box<dyn Write> = match path.as_str() {
+            io::STDIO_PATH => Box::new(std::io::stderr()),
+            path => io::open_output(Some(path))?,
+        };
+        summary.write(output.format.unwrap_or(config.output.format), &mut out)
+            .map_err(|source| Error::Io { path: path.clone(), source })?;
+    }
+    Ok(engine.into_store())
+}
+
+/// Read and parse the input on a thread of its own, handing the rows and their line over through a bounded queue
+fn read_ahead(path: String, format: InputFormat) -> impl Iterator<Item = (u64, Result<Transaction, Error>)> {
+    let (sender, receiver) = mpsc::sync_channel(READ_AHEAD_ROWS);
+    thread::spawn(move || {
+        let mut reader = match TransactionReader::with_format(&path, format) {
+            Ok(reader) => reader,
+            Err(err) => {
+                let _ = sender.send((0, Err(err)));
+                return;
+            },
+        };
+        while let Some(row) = reader.next() {
+            // The receiving end goes away when processing stops at a parse error
+            if sender.send((reader.line(), row)).is_err() { break; }
+        }
+    });
+    receiver.into_iter()
+}
+
+/// Create an engine processing into the store with the rules, buffering and policies from the configuration
+fn engine(store: Store, config: &Config) -> Engine {
+    Engine::new(store, config.rules.clone(), config.pending).with_policies(policy::build_policies(&config.policies))
+}
+
+/// Warn about buffered references which gave up waiting for their transaction and transactions flagged for review
+fn report_warnings(engine: &mut Engine) {
+    for Settled { transaction, result } in engine.take_settled() {
+        if result == Err(TransactionError::Orphaned) {
+            eprintln!("warning: orphaned {} for client {} referencing transaction {} which never arrived",
+                    transaction.kind().name(), transaction.client_id(), transaction.id());
+        }
+        if let (Some(batch), Err(err)) = (transaction.batch(), result) {
+            eprintln!("warning: batch {} rejected, {} {} for client {} failed with {}",
+                    batch, transaction.kind().name(), transaction.id(), transaction.client_id(), err);
+        }
+    }
+    for Flag { transaction, reason } in engine.take_flagged() {
+        eprintln!("review: {} {} for client {} flagged by {}",
+                transaction.kind().name(), transaction.id(), transaction.client_id(), reason);
+    }
+}
+
+/// Write the client accounts, optionally report overdrawn and owing accounts and disputes separately and snapshot the
+/// store
+fn finish(
+    store: &Store, output: &OutputArgs, save_snapshot: Option<&str>, reports: &ReportArgs, config: &Config,
+) -> Result<(), Error> {
+    let format = output.format.unwrap_or(config.output.format);
+    let path = output.output.as_deref();
+    let mut out = io::open_output(path)?;
+    io::write_clients(store, format, &mut out)
+        .map_err(|source| Error::Io { path: path.unwrap_or(io::STDIO_PATH).to_string(), source })?;
+
+    if let Some(path) = &reports.overdrawn {
+        io::write_overdrawn(store, format, &mut io::open_output(Some(path))?)
+            .map_err(|source| Error::Io { path: path.clone(), source })?;
+    }
+    if let Some(path) = &reports.exposure {
+        io::write_exposure(store, format, &mut io::open_output(Some(path))?)
+            .map_err(|source| Error::Io { path: path.clone(), source })?;
+    }
+    if let Some(path) = &reports.disputes {
+        io::write_disputes(store, format, &mut io::open_output(Some(path))?)
+            .map_err(|source| Error::Io { path: path.clone(), source })?;
+    }
+    if let Some(path) = &reports.disputes_by_reason {
+        io::write_disputes_by_reason(store, format, &mut io::open_output(Some(path))?)
+            .map_err(|source| Error::Io { path: path.clone(), source })?;
+    }
+    if let Some(path) = &reports.disputes_by_client {
+        io::write_disputes_by_client(store, format, &mut io::open_output(Some(path))?)
+            .map_err(|source| Error::Io { path: path.clone(), source })?;
+    }
+
+    match save_snapshot {
+        Some(path) => store.save_snapshot(path),
+        None => Ok(()),
+    }
+}
+
+/// Write the stored transactions of a client ordered by transaction id
+fn history(store: &Store, client: ClientId, output: &OutputArgs, config: &Config) -> Result<(), Error> {
+    let path = output.output.as_deref();
+    io::write_history(store, client, output.format.unwrap_or(config.output.format), &mut io::open_output(path)?)
+        .map_err(|source| Error::Io { path: path.unwrap_or(io::STDIO_PATH).to_string(), source })
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use crate::id::num_id;
+
+    use super::*;
+
+    #[test]
+    fn input_without_subcommand() {
+        let cli = Cli::try_parse_from(["transactions", "example.csv"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.input.as_deref(), Some("example.csv"));
+    }
+
+    #[test]
+    fn process_options() {
+        let cli = Cli::try_parse_from([
+            "transactions", "process", "in.json", "--input-format", "ndjson", "--format", "json", "-o", "out.json",
+        ]).unwrap();
+        match cli.command {
+            Some(Command::Process { input, output, run }) => {
+                assert!(run.save_snapshot.is_none());
+                assert_eq!(input.input_format, Some(InputFormat::Ndjson));
+                assert_eq!(output.format, Some(OutputFormat::Json));
+                assert_eq!(output.output.as_deref(), Some("out.json"));
+            },
+            command => panic!("unexpected command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn summary_option() {
+        let cli = Cli::try_parse_from(["transactions", "process", "in.csv", "--summary"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Process { run, .. }) if run.summary.as_deref() == Some("-")));
+        let cli = Cli::try_parse_from(["transactions", "process", "--summary", "in.csv"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Process { input, run, .. })
+                if input.path == "in.csv" && run.summary.as_deref() == Some("-")));
+        let cli = Cli::try_parse_from(["transactions", "replay", "--snapshot", "s.json", "in.csv", "--summary=run.txt"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Replay { run, .. }) if run.summary.as_deref() == Some("run.txt")));
+    }
+
+    #[test]
+    fn config_before_subcommand() {
+        let cli = Cli::try_parse_from(["transactions", "--config", "config.toml", "process", "in.csv"]).unwrap();
+        assert_eq!(cli.config.as_deref(), Some("config.toml"));
+        assert!(matches!(cli.command, Some(Command::Process { .. })));
+    }
+
+    #[test]
+    fn missing_input() {
+        let cli = Cli::try_parse_from(["transactions"]).unwrap();
+        assert!(matches!(run(cli), Err(Error::Usage(_))));
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let path = std::env::temp_dir().join(format!("transactions-cli-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut store = Store::new();
+        store.get_or_create_client(num_id(1));
+        store.save_snapshot(path).unwrap();
+
+        let result = run(Cli::try_parse_from(["transactions", "diff", path, path]).unwrap());
+        std::fs::remove_file(path).unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn strict_opening_balances() {
+        let dir = std::env::temp_dir();
+        let master = dir.join(format!("transactions-cli-master-{}.csv", std::process::id()));
+        let openings = dir.join(format!("transactions-cli-openings-{}.csv", std::process::id()));
+        let (master, openings) = (master.to_str().unwrap(), openings.to_str().unwrap());
+        std::fs::write(master, "client,name,status,kyc_tier
+1,Ada,active,1
+").unwrap();
+        std::fs::write(openings, "client,available,held,total,locked
+1,10,0,10,false
+2,5,0,5,false
+").unwrap();
+        let config = |strict: bool| Config::parse("test.toml", &format!(
+            "[accounts]\nmaster = {:?}\nopening_balances = {:?}\nstrict = {}\n", master, openings, strict,
+        )).unwrap();
+
+        let lenient = prepare(Store::new(), &config(false));
+        let strict = prepare(Store::new(), &config(true));
+        std::fs::remove_file(master).unwrap();
+        std::fs::remove_file(openings).unwrap();
+
+        assert!(lenient.unwrap().get_client(num_id(2)).is_some());
+        assert!(matches!(strict, Err(Error::Parse { line: Some(3), message, .. })
+                if message == "client 2 isn't in the client master file"));
+    }
+
+}
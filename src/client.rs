@@ -1,14 +1,70 @@
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 
+use crate::dispute::DisputeId;
+
+/// ID of a client account, widened by the `u64-ids` and `string-ids` features
+#[cfg(not(any(feature = "u64-ids", feature = "string-ids")))]
 pub type ClientId = u16;
+#[cfg(all(feature = "u64-ids", not(feature = "string-ids")))]
+pub type ClientId = u64;
+#[cfg(feature = "string-ids")]
+pub type ClientId = crate::id::StrId;
+
+/// Standing of a client in the client master file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum ClientStatus {
+    /// The account is in normal use
+    Active,
+    /// The account is temporarily out of use, such as while under review
+    Suspended,
+    /// The account was closed and shouldn't see any more activity
+    Closed,
+}
+
+impl ClientStatus {
+    /// Name of the status as used in the client master file and reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientStatus::Active => "active",
+            ClientStatus::Suspended => "suspended",
+            ClientStatus::Closed => "closed",
+        }
+    }
+}
+
+/// Master data of a client, carried along with the account into outputs and reports
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientProfile {
+    pub name: String,
+    pub status: ClientStatus,
+    /// Level of identity verification the client passed
+    pub kyc_tier: u8,
+}
 
 /// Representation of the client account in storage
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Client {
     id: ClientId,
     available: Decimal,
     held: Decimal,
     locked: bool,
+    /// How far below zero the available funds are allowed to go
+    #[serde(default)]
+    credit_limit: Decimal,
+    /// Part of the negative available funds drawn from the credit limit by withdrawals, repaid first
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    credit_used: Decimal,
+    /// Disputed funds which couldn't be held as the client had already spent them
+    #[serde(default)]
+    shortfall: Decimal,
+    /// Disputes whose chargebacks locked the account
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    locked_by: Vec<DisputeId>,
+    /// Master data of the client, if a client master file was loaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile: Option<ClientProfile>,
 }
 
 
@@ -18,6 +74,15 @@ impl Client {
     pub fn available(&self) -> Decimal { self.available }
     pub fn held(&self) -> Decimal { self.held }
     pub fn locked(&self) -> bool { self.locked }
+    pub fn credit_limit(&self) -> Decimal { self.credit_limit }
+    pub fn credit_used(&self) -> Decimal { self.credit_used }
+    pub fn shortfall(&self) -> Decimal { self.shortfall }
+    pub fn locked_by(&self) -> &[DisputeId] { &self.locked_by }
+    pub fn profile(&self) -> Option<&ClientProfile> { self.profile.as_ref() }
+
+    /// Whether the client has withdrawn more than their available funds using their credit limit, available funds
+    /// below zero through disputes over spent funds don't count
+    pub fn overdrawn(&self) -> bool { self.credit_used > Decimal::ZERO }
 
     /// Calculate total based on available funds and funds held in dispute
     pub fn total(&self) -> Decimal { self.available + self.held }
@@ -25,51 +90,155 @@ impl Client {
     /// Apply deposited funds into the client's account
     pub fn deposit(&mut self, amount: Decimal) {
         self.available += amount;
+        self.repay_credit();
     }
 
     /// Subtract available funds from user's account (return true or false based on whether operation was successful)
     pub fn withdraw(&mut self, amount: Decimal) -> bool {
-        if self.available < amount { return false; }
+        if self.available + self.credit_limit < amount { false }
         else {
+            self.credit_used += (amount - self.available.max(Decimal::ZERO)).max(Decimal::ZERO);
             self.available -= amount;
-            return true;
+            true
+        }
+    }
+
+    /// Take a reversed deposit back out of the available funds, without drawing on the credit limit as nothing was
+    /// withdrawn (return whether the funds are still available)
+    pub fn reverse_deposit(&mut self, amount: Decimal) -> bool {
+        if self.available < amount { false }
+        else {
+            self.available -= amount;
+            true
         }
     }
 
     /// Transfer an amount of available into "held" for the dispute (return status of whether the operation is possible)
     pub fn dispute(&mut self, amount: Decimal) -> bool {
-        if self.available < amount { return false; }
+        if self.available < amount { false }
         else {
             self.available -= amount;
             self.held += amount;
-            return true;
+            true
+        }
+    }
+
+    /// Hold the whole disputed amount even if it takes the available funds below zero, recording the debt there
+    pub fn dispute_overdrawing(&mut self, amount: Decimal) {
+        self.available -= amount;
+        self.held += amount;
+    }
+
+    /// Hold as much of the disputed amount as is still available, recording the rest as a shortfall which is returned
+    pub fn dispute_remaining(&mut self, amount: Decimal) -> Decimal {
+        let held = amount.min(self.available.max(Decimal::ZERO));
+        self.available -= held;
+        self.held += held;
+        self.shortfall += amount - held;
+        amount - held
+    }
+
+    /// Drop part of the shortfall once the dispute it was recorded for is resolved in the client's favour
+    pub fn settle_shortfall(&mut self, amount: Decimal) {
+        self.shortfall -= amount;
+    }
+
+    /// Provisionally credit the withdrawn amount of a disputed withdrawal into held
+    pub fn recredit(&mut self, amount: Decimal) {
+        self.held += amount;
+    }
+
+    /// Take back a provisional credit from held once the withdrawal dispute is resolved (return whether it's possible)
+    pub fn revoke_recredit(&mut self, amount: Decimal) -> bool {
+        if self.held < amount { false }
+        else {
+            self.held -= amount;
+            true
+        }
+    }
+
+    /// Return a provisional credit from held to available when the withdrawal is charged back
+    pub fn chargeback_recredit(&mut self, amount: Decimal) -> bool {
+        if self.held < amount { false }
+        else {
+            self.held -= amount;
+            self.available += amount;
+            self.repay_credit();
+            true
         }
     }
 
     /// Return funds held in dispute (return true or false depending if successful)
     pub fn resolve(&mut self, amount: Decimal) -> bool {
-        if self.held < amount { return false; }
+        if self.held < amount { false }
         else {
             self.held -= amount;
             self.available += amount;
-            return true;
+            self.repay_credit();
+            true
         }
     }
     
-    /// Remove money held in dispute and lock the account
+    /// Remove money held in dispute, the account is locked separately as the rules decide
     pub fn chargeback(&mut self, amount: Decimal) -> bool {
-        if self.held < amount { return false; }
+        if self.held < amount { false }
         else {
-            self.locked = true;
             self.held -= amount;
-            return true;
+            true
         }
     }
 
-    /// TODO: this is used by the tests and would most definitely be used in a larger codebase
-    #[allow(dead_code)]
+    /// Note the dispute whose chargeback locked the account
+    pub fn lock_for(&mut self, dispute: DisputeId) {
+        self.locked = true;
+        self.locked_by.push(dispute);
+    }
+
+    /// Give back the held funds a chargeback removed, the client's available funds for a disputed deposit and the
+    /// provisional credit for a disputed withdrawal
+    pub fn reverse_chargeback(&mut self, amount: Decimal, withdrawal: bool) {
+        if withdrawal { self.available -= amount; } else { self.available += amount; }
+        self.repay_credit();
+    }
+
+    /// Funds coming back into available pay off the credit drawn by withdrawals before anything else
+    fn repay_credit(&mut self) {
+        self.credit_used = self.credit_used.min(-self.available).max(Decimal::ZERO);
+    }
+
+    /// Drop a reversed chargeback from the reasons for the lock, unlocking the account if `unlock` is set and it was
+    /// the last one (return whether it was unlocked)
+    pub fn release_lock(&mut self, dispute: DisputeId, unlock: bool) -> bool {
+        let Some(position) = self.locked_by.iter().position(|id| *id == dispute) else { return false };
+        self.locked_by.remove(position);
+        if !unlock || !self.locked_by.is_empty() { return false; }
+        self.locked = false;
+        true
+    }
+
+    /// Set how far below zero the available funds are allowed to go, existing overdrafts are kept if it's lowered
+    pub fn set_credit_limit(&mut self, limit: Decimal) {
+        self.credit_limit = limit;
+    }
+
+    /// Set the balances the account was migrated with, replacing whatever it held
+    pub fn open(&mut self, available: Decimal, held: Decimal, locked: bool) {
+        self.available = available;
+        self.held = held;
+        self.locked = locked;
+        // A migrated overdraft is taken to be drawn from the credit limit
+        self.credit_used = (-available).max(Decimal::ZERO);
+    }
+
+    /// Attach the client's master data to the account
+    pub fn set_profile(&mut self, profile: ClientProfile) {
+        self.profile = Some(profile);
+    }
+
+    /// Create a client account with a known state
     pub fn new(id: ClientId, available: Decimal, held: Decimal, locked: bool) -> Client {
-        Client { id, available, held, locked }
+        Client { id, available, held, locked, credit_limit: Decimal::ZERO, credit_used: (-available).max(Decimal::ZERO),
+            shortfall: Decimal::ZERO, locked_by: Vec::new(), profile: None }
     }
 
     /// Get a new empty client account with a provided ID
@@ -79,6 +248,11 @@ impl Client {
             available: Decimal::new(0, 4), 
             held: Decimal::new(0, 4), 
             locked: false,
+            credit_limit: Decimal::ZERO,
+            credit_used: Decimal::ZERO,
+            shortfall: Decimal::ZERO,
+            locked_by: Vec::new(),
+            profile: None,
         }
     }
 }
@@ -90,24 +264,28 @@ impl Client {
 mod tests {
     use std::str::FromStr;
 
+    use crate::id::num_id;
+
     use super::*;
 
     #[test]
     fn create_default_client() {
-        let test_id = 1;
+        let test_id = num_id(1);
         let default_client = Client{
             id: test_id, available: Decimal::new(0, 4), 
-            held: Decimal::new(0, 4), locked: false};
+            held: Decimal::new(0, 4), locked: false, credit_limit: Decimal::ZERO, credit_used: Decimal::ZERO,
+            shortfall: Decimal::ZERO, locked_by: Vec::new(), profile: None};
         assert_eq!(default_client, Client::default(test_id));
     }
 
     #[test]
     fn create_custom_client() {
-        let test_id = 1;
+        let test_id = num_id(1);
 
         let test_client = Client{
             id: test_id, available: Decimal::new(534543654, 4), 
-            held: Decimal::new(543324534543, 4), locked: false};
+            held: Decimal::new(543324534543, 4), locked: false, credit_limit: Decimal::ZERO, credit_used: Decimal::ZERO,
+            shortfall: Decimal::ZERO, locked_by: Vec::new(), profile: None};
 
         let custom_client = Client::new(
             test_id, Decimal::new(534543654, 4), 
@@ -119,7 +297,7 @@ mod tests {
     #[test]
     fn deposit() {
         let mut test_client = Client::new(
-            1, Decimal::new(0, 4), 
+            num_id(1), Decimal::new(0, 4), 
             Decimal::new(0, 4), false);
 
         test_client.deposit(Decimal::from_str("125.2563").unwrap());
@@ -132,7 +310,7 @@ mod tests {
     #[test]
     fn withdraw() {
         let mut test_client = Client::new(
-            1, Decimal::new(0, 4), 
+            num_id(1), Decimal::new(0, 4), 
             Decimal::new(0, 4), false);
 
         test_client.deposit(Decimal::from_str("125.2563").unwrap());
@@ -142,10 +320,42 @@ mod tests {
         assert_eq!(test_client.available.to_string(), "150.5126");
     }
    
+    #[test]
+    fn withdraw_with_credit_limit() {
+        let mut test_client = Client::default(num_id(1));
+        test_client.deposit(Decimal::from(50));
+        assert!(!test_client.withdraw(Decimal::from(100)));
+
+        test_client.set_credit_limit(Decimal::from(75));
+        assert!(test_client.withdraw(Decimal::from(100)));
+        assert_eq!(test_client.available(), Decimal::from(-50));
+        assert!(test_client.overdrawn());
+        assert!(!test_client.withdraw(Decimal::from(30)));
+    }
+
+    #[test]
+    fn credit_used() {
+        let mut test_client = Client::default(num_id(1));
+        test_client.set_credit_limit(Decimal::from(100));
+        test_client.deposit(Decimal::from(20));
+        test_client.dispute_overdrawing(Decimal::from(30));
+        assert_eq!(test_client.available(), Decimal::from(-10));
+        assert!(!test_client.overdrawn());
+
+        test_client.withdraw(Decimal::from(40));
+        assert_eq!(test_client.credit_used(), Decimal::from(40));
+        // Funds coming back pay off the credit first
+        assert!(test_client.resolve(Decimal::from(30)));
+        assert_eq!((test_client.available(), test_client.credit_used()), (Decimal::from(-20), Decimal::from(20)));
+        test_client.deposit(Decimal::from(25));
+        assert_eq!(test_client.credit_used(), Decimal::ZERO);
+        assert!(!test_client.overdrawn());
+    }
+   
     #[test]
     fn dispute() {
         let mut test_client = Client::new(
-            1, Decimal::new(0, 4), 
+            num_id(1), Decimal::new(0, 4), 
             Decimal::new(0, 4), false);
 
         test_client.deposit(Decimal::from_str("225.2563").unwrap());
@@ -155,10 +365,45 @@ mod tests {
         assert_eq!(test_client.held.to_string(), "100");
     }
    
+    #[test]
+    fn dispute_spent_funds() {
+        let mut overdrawing = Client::default(num_id(1));
+        overdrawing.deposit(Decimal::from(30));
+        overdrawing.dispute_overdrawing(Decimal::from(100));
+        assert_eq!((overdrawing.available(), overdrawing.held()), (Decimal::from(-70), Decimal::from(100)));
+
+        let mut remaining = Client::default(num_id(2));
+        remaining.deposit(Decimal::from(30));
+        assert_eq!(remaining.dispute_remaining(Decimal::from(100)), Decimal::from(70));
+        assert_eq!((remaining.available(), remaining.held()), (Decimal::ZERO, Decimal::from(30)));
+        assert_eq!(remaining.shortfall(), Decimal::from(70));
+    }
+
+    #[test]
+    fn release_lock() {
+        let mut test_client = Client::default(num_id(1));
+        test_client.lock_for(3);
+        assert!(!test_client.release_lock(3, false));
+        assert!(test_client.locked());
+
+        test_client.lock_for(4);
+        test_client.lock_for(5);
+        assert!(!test_client.release_lock(4, true));
+        assert!(test_client.locked());
+        assert!(test_client.release_lock(5, true));
+        assert!(!test_client.locked());
+
+        let mut test_client = Client::default(num_id(2));
+        test_client.lock_for(3);
+        assert!(test_client.release_lock(3, true));
+        assert!(!test_client.locked());
+        assert!(test_client.locked_by().is_empty());
+    }
+
     #[test]
     fn resolve() {
         let mut test_client = Client::new(
-            1, Decimal::new(0, 4), 
+            num_id(1), Decimal::new(0, 4), 
             Decimal::new(0, 4), false);
 
         test_client.deposit(Decimal::from_str("125.2563").unwrap());
@@ -172,7 +417,7 @@ mod tests {
     #[test]
     fn chargeback() {
         let mut test_client = Client::new(
-            1, Decimal::new(0, 4), 
+            num_id(1), Decimal::new(0, 4), 
             Decimal::new(0, 4), false);
 
         test_client.deposit(Decimal::from_str("125.2563").unwrap());
@@ -186,7 +431,7 @@ mod tests {
     #[test]
     fn properties() {
         let mut test_client = Client::new(
-            1, Decimal::new(0, 4), 
+            num_id(1), Decimal::new(0, 4), 
             Decimal::new(0, 4), false);
 
         test_client.deposit(Decimal::from_str("125.2563").unwrap());
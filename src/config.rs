@@ -0,0 +1,407 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use chrono::TimeDelta;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    dispute::ReasonCode, engine::BufferPolicy, error::Error, io::{InputFormat, OutputFormat}, logging::LogConfig,
+    policy::PolicyConfig, store::RetentionPolicy,
+};
+
+
+/// Prefix of the environment variables overriding settings, `TRANSACTIONS_<SECTION>__<KEY>`
+pub const ENV_PREFIX: &str = "TRANSACTIONS_";
+
+
+/// Settings loaded from the TOML file given with `--config`, every section is optional
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub input: InputConfig,
+    pub output: OutputConfig,
+    pub retention: RetentionPolicy,
+    pub rules: Rules,
+    pub pending: BufferPolicy,
+    pub engine: EngineConfig,
+    pub storage: StorageConfig,
+    pub accounts: AccountsConfig,
+    /// Policies consulted around every transaction, in the order they are listed
+    pub policies: Vec<PolicyConfig>,
+    pub logging: LogConfig,
+}
+
+/// Defaults for reading transactions
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub format: InputFormat,
+}
+
+/// Defaults for writing client accounts
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+}
+
+/// How the engine is run
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    /// Parse rows on a thread of their own while the engine executes the rows parsed before them
+    pub read_ahead: bool,
+}
+
+/// Where the store lives between runs
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Snapshot file of the `snapshot` backend
+    pub path: Option<String>,
+}
+
+/// Kinds of storage holding the accounts and transactions
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum StorageBackend {
+    /// Start every run from empty accounts and keep nothing once it is done
+    #[default]
+    Memory,
+    /// Resume from the snapshot at the storage path if there is one and save back to it after processing
+    Snapshot,
+}
+
+/// Per-client settings applied to the accounts
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// CSV file with `client,credit_limit` rows giving clients an agreed overdraft
+    pub credit_limits: Option<String>,
+    /// Accounts file in the shape of the output with the balances accounts are opened with before processing
+    pub opening_balances: Option<String>,
+    /// CSV file with `client,name,status,kyc_tier` rows of client master data
+    pub master: Option<String>,
+    /// Only accept transactions of clients listed in the master file or with an account already
+    pub strict: bool,
+}
+
+/// Rules deciding which transactions are accepted and how they are applied
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    /// Days after a transaction during which it can still be disputed, unlimited if not set
+    pub dispute_window_days: Option<u32>,
+    pub withdrawal_disputes: WithdrawalDisputes,
+    pub spent_disputes: SpentDisputes,
+    /// Unlock an account when the chargeback it was locked by, and no other, is reversed
+    pub reversal_unlocks: bool,
+    /// Rules for the disputes with a given reason, overriding the ones above
+    pub reasons: BTreeMap<ReasonCode, ReasonRules>,
+}
+
+/// Rules for the disputes with one reason code
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReasonRules {
+    /// Whether a chargeback of the dispute locks the account
+    pub locks: bool,
+    /// Days after a transaction during which it can still be disputed for the reason, the rules' window if not set
+    pub dispute_window_days: Option<u32>,
+}
+
+impl Default for ReasonRules {
+    fn default() -> ReasonRules {
+        ReasonRules { locks: true, dispute_window_days: None }
+    }
+}
+
+/// What a dispute over a withdrawal does, the withdrawn funds having already left the account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum WithdrawalDisputes {
+    /// Withdrawals can't be disputed
+    #[default]
+    Rejected,
+    /// The withdrawn amount is provisionally credited to held, a resolve takes it back out and a chargeback returns it
+    /// to the client's available funds
+    Recredit,
+}
+
+/// What a dispute over a deposit does when the client has already spent some of the deposited funds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum SpentDisputes {
+    /// The dispute fails with `insufficient_funds`
+    #[default]
+    Reject,
+    /// The whole amount is held, taking the available funds below zero as a debt of the client
+    AllowNegative,
+    /// What is left of the available funds is held and the rest is recorded as a shortfall
+    HoldRemaining,
+}
+
+impl Rules {
+    /// Longest time between a transaction and a dispute over it with the given reason
+    pub fn dispute_window(&self, reason: Option<ReasonCode>) -> Option<TimeDelta> {
+        self.for_reason(reason).dispute_window_days.or(self.dispute_window_days).map(|days| TimeDelta::days(days.into()))
+    }
+
+    /// Rules for disputes with the given reason, the defaults for those without one or without rules of their own
+    pub fn for_reason(&self, reason: Option<ReasonCode>) -> ReasonRules {
+        reason.and_then(|reason| self.reasons.get(&reason)).copied().unwrap_or_default()
+    }
+}
+
+
+impl Config {
+    /// Read and parse the configuration file at the given path, with overrides from the environment
+    pub fn load(path: &str) -> Result<Config, Error> {
+        let text = fs::read_to_string(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+        Config::parse_with_overrides(path, &text, std::env::vars())
+    }
+
+    /// Configuration made of the defaults and the overrides from the environment, used without a configuration file
+    pub fn from_env() -> Result<Config, Error> {
+        Config::parse_with_overrides("environment", "", std::env::vars())
+    }
+
+    /// Parse configuration from TOML text, the path is only used for error reporting
+    pub fn parse(path: &str, text: &str) -> Result<Config, Error> {
+        Config::parse_with_overrides(path, text, std::iter::empty())
+    }
+
+    /// Parse configuration from TOML text with the settings overridden by the `TRANSACTIONS_<SECTION>__<KEY>`
+    /// variables, then check the settings make sense together
+    pub fn parse_with_overrides(
+        path: &str, text: &str, vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Config, Error> {
+        let error = |message: String| Error::Config { path: path.to_string(), message };
+        let mut table: toml::Table = toml::from_str(text).map_err(|err| error(err.to_string()))?;
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX).filter(|key| key.contains("__")) else { continue };
+            let key = key.to_lowercase();
+            let parsed = parse_value(&value);
+            let string = parsed.is_str();
+            override_value(&mut table, &key, parsed).map_err(|message| error(format!("{}: {}", name, message)))?;
+
+            // Values which read as numbers or booleans are still strings to the settings expecting one
+            if !string && table.clone().try_into::<Config>().is_err() {
+                let mut text = table.clone();
+                override_value(&mut text, &key, toml::Value::String(value)).map_err(error)?;
+                if text.clone().try_into::<Config>().is_ok() { table = text; }
+            }
+        }
+
+        let config: Config = table.try_into().map_err(|err: toml::de::Error| error(err.to_string()))?;
+        config.validate().map_err(error)?;
+        Ok(config)
+    }
+
+    /// Check the settings which parse but can't work, describing the first problem found
+    fn validate(&self) -> Result<(), String> {
+        if self.retention.max_count == Some(0) {
+            return Err("retention.max_count must be at least 1".to_string());
+        }
+        if self.pending.max_rows == Some(0) || self.pending.max_age_seconds == Some(0) {
+            return Err("pending limits must be at least 1, leave them out to disable buffering".to_string());
+        }
+        if self.storage.backend == StorageBackend::Snapshot && self.storage.path.is_none() {
+            return Err("storage.path is required by the snapshot backend".to_string());
+        }
+        if self.accounts.strict && self.accounts.master.is_none() {
+            return Err("accounts.master is required by strict accounts".to_string());
+        }
+        if let Err(err) = EnvFilter::try_new(&self.logging.level) {
+            return Err(format!("invalid logging.level `{}`: {}", self.logging.level, err));
+        }
+        for (index, policy) in self.policies.iter().enumerate() {
+            policy.validate().map_err(|message| format!("policies[{}]: {}", index, message))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse an overriding value as TOML, falling back to a string
+fn parse_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", value)).ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// Set the value at the `__` separated path of keys in the table
+fn override_value(table: &mut toml::Table, key: &str, value: toml::Value) -> Result<(), String> {
+    let (section, key) = key.rsplit_once("__").unwrap_or(("", key));
+    let mut target = table;
+    for name in section.split("__").filter(|name| !name.is_empty()) {
+        target = match target.entry(name).or_insert_with(|| toml::Value::Table(toml::Table::new())) {
+            toml::Value::Table(inner) => inner,
+            _ => return Err(format!("`{}` is not a section", name)),
+        };
+    }
+
+    target.insert(key.to_string(), value);
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::LogFormat;
+
+    use super::*;
+
+    #[test]
+    fn empty_config() {
+        assert_eq!(Config::parse("test.toml", "").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn formats() {
+        let config = Config::parse("test.toml", "[input]\nformat = \"ndjson\"\n[output]\nformat = \"json\"\n").unwrap();
+        assert_eq!(config.input.format, InputFormat::Ndjson);
+        assert_eq!(config.output.format, OutputFormat::Json);
+    }
+
+    #[test]
+    fn retention() {
+        let config = Config::parse("test.toml", "[retention]\nmax_count = 1000\nevict_finalized = true\n").unwrap();
+        assert_eq!(config.retention.max_count, Some(1000));
+        assert_eq!(config.retention.max_age, None);
+        assert!(config.retention.evict_finalized);
+    }
+
+    #[test]
+    fn rules() {
+        let config = Config::parse("test.toml", "[rules]\ndispute_window_days = 120\n").unwrap();
+        assert_eq!(config.rules.dispute_window(None), Some(TimeDelta::days(120)));
+        assert_eq!(config.rules.withdrawal_disputes, WithdrawalDisputes::Rejected);
+
+        let config = Config::parse("test.toml", "[rules]\nwithdrawal_disputes = \"recredit\"\n").unwrap();
+        assert_eq!(config.rules.withdrawal_disputes, WithdrawalDisputes::Recredit);
+
+        let config = Config::parse("test.toml", "[rules]\nspent_disputes = \"hold_remaining\"\n").unwrap();
+        assert_eq!(config.rules.spent_disputes, SpentDisputes::HoldRemaining);
+        assert!(!config.rules.reversal_unlocks);
+    }
+
+    #[test]
+    fn reason_rules() {
+        let text = "[rules]\ndispute_window_days = 120\n[rules.reasons.fraud]\ndispute_window_days = 540\n\
+                [rules.reasons.not_received]\nlocks = false\n";
+        let rules = Config::parse("test.toml", text).unwrap().rules;
+        assert_eq!(rules.dispute_window(Some(ReasonCode::Fraud)), Some(TimeDelta::days(540)));
+        assert_eq!(rules.dispute_window(Some(ReasonCode::NotReceived)), Some(TimeDelta::days(120)));
+        assert!(rules.for_reason(Some(ReasonCode::Fraud)).locks);
+        assert!(!rules.for_reason(Some(ReasonCode::NotReceived)).locks);
+        assert!(rules.for_reason(None).locks);
+
+        assert!(Config::parse("test.toml", "[rules.reasons.theft]\nlocks = false\n").is_err());
+    }
+
+    #[test]
+    fn pending() {
+        let config = Config::parse("test.toml", "[pending]\nmax_rows = 500\n").unwrap();
+        assert_eq!(config.pending, BufferPolicy { max_rows: Some(500), max_age_seconds: None });
+        assert!(config.pending.enabled());
+    }
+
+    #[test]
+    fn accounts() {
+        let config = Config::parse("test.toml", "[accounts]\ncredit_limits = \"limits.csv\"\n").unwrap();
+        assert_eq!(config.accounts.credit_limits.as_deref(), Some("limits.csv"));
+        assert!(!config.accounts.strict);
+        assert!(config.accounts.opening_balances.is_none());
+
+        let config = Config::parse("test.toml", "[accounts]\nmaster = \"clients.csv\"\nstrict = true\n").unwrap();
+        assert_eq!(config.accounts.master.as_deref(), Some("clients.csv"));
+        assert!(config.accounts.strict);
+        assert!(matches!(Config::parse("test.toml", "[accounts]\nstrict = true\n"), Err(Error::Config { .. })));
+    }
+
+    #[test]
+    fn policies() {
+        let config = Config::parse("test.toml", concat!(
+            "[[policies]]\ntype = \"max_amount\"\nmax = 1000\n",
+            "[[policies]]\ntype = \"blocked_clients\"\nclients = [4, 5]\naction = \"review\"\n",
+        )).unwrap();
+        assert_eq!(config.policies.len(), 2);
+        assert!(matches!(config.policies[1], PolicyConfig::BlockedClients { .. }));
+
+        let err = Config::parse("test.toml", "[[policies]]\ntype = \"max_amount\"\nmax = 1\nmin = 0\n").unwrap_err();
+        assert!(matches!(err, Error::Config { .. }));
+    }
+
+    #[test]
+    fn logging() {
+        let config = Config::parse("test.toml", "[logging]\nlevel = \"debug\"\nformat = \"json\"\n").unwrap();
+        assert_eq!(config.logging.level, "debug");
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(Config::default().logging.level, "warn");
+    }
+
+    #[test]
+    fn engine_and_storage() {
+        let config = Config::parse("test.toml", "[engine]\nread_ahead = true\n[storage]\nbackend = \"snapshot\"\npath = \"state.json\"\n").unwrap();
+        assert!(config.engine.read_ahead);
+        assert_eq!(config.storage, StorageConfig { backend: StorageBackend::Snapshot, path: Some("state.json".to_string()) });
+        assert!(!Config::default().engine.read_ahead);
+        assert!(matches!(Config::parse("test.toml", "[engine]\nthreads = 2\n"), Err(Error::Config { .. })));
+    }
+
+    #[test]
+    fn env_overrides() {
+        let vars = [
+            ("TRANSACTIONS_RULES__DISPUTE_WINDOW_DAYS", "30"),
+            ("TRANSACTIONS_OUTPUT__FORMAT", "json"),
+            ("TRANSACTIONS_LOGGING__LEVEL", "transactions::store=trace"),
+            ("TRANSACTIONS_CONFIG", "ignored.toml"),
+            ("HOME", "/root"),
+        ].map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = Config::parse_with_overrides("test.toml", "[rules]\ndispute_window_days = 120\n", vars).unwrap();
+        assert_eq!(config.rules.dispute_window_days, Some(30));
+        assert_eq!(config.output.format, OutputFormat::Json);
+        assert_eq!(config.logging.level, "transactions::store=trace");
+
+        // Strings which read as other TOML values
+        let vars = [
+            ("TRANSACTIONS_ACCOUNTS__MASTER", "2024"),
+            ("TRANSACTIONS_LOGGING__LEVEL", "true"),
+            ("TRANSACTIONS_ENGINE__READ_AHEAD", "true"),
+        ].map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = Config::parse_with_overrides("test.toml", "", vars).unwrap();
+        assert_eq!(config.accounts.master.as_deref(), Some("2024"));
+        assert_eq!(config.logging.level, "true");
+        assert!(config.engine.read_ahead);
+
+        let vars = [("TRANSACTIONS_OUTPUT__COLOUR".to_string(), "true".to_string())];
+        assert!(matches!(Config::parse_with_overrides("test.toml", "", vars), Err(Error::Config { .. })));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        for (text, message) in [
+            ("[retention]\nmax_count = 0\n", "retention.max_count"),
+            ("[pending]\nmax_rows = 0\n", "pending limits"),
+            ("[storage]\nbackend = \"snapshot\"\n", "storage.path is required"),
+            ("[logging]\nlevel = \"transactions=loud\"\n", "invalid logging.level"),
+            ("[[policies]]\ntype = \"velocity\"\nwindow_seconds = 60\n", "policies[0]: velocity needs max_count"),
+            ("[[policies]]\ntype = \"max_amount\"\nmax = -1\n", "policies[0]: max can't be negative"),
+        ] {
+            match Config::parse("test.toml", text) {
+                Err(Error::Config { message: found, .. }) => assert!(found.contains(message), "{}: {}", message, found),
+                result => panic!("{} accepted: {:?}", message, result),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_key() {
+        let err = Config::parse("test.toml", "[output]\ncolour = true\n").unwrap_err();
+        assert!(matches!(err, Error::Config { .. }));
+    }
+
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{client::ClientId, error::Error, store::Store, io::{self as output, AccountRecord, OutputFormat}};
+
+
+/// How close two amounts have to be to be considered the same
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Tolerance {
+    /// Largest absolute difference between two amounts which is ignored
+    pub absolute: Decimal,
+    /// Number of decimal places both amounts are rounded to before being compared
+    pub decimal_places: Option<u32>,
+}
+
+/// Differences in the account of a single client
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientDiff {
+    pub client: ClientId,
+    /// Account on the left, missing if the client only exists on the right
+    pub left: Option<AccountRecord>,
+    /// Account on the right, missing if the client only exists on the left
+    pub right: Option<AccountRecord>,
+    /// Names of the fields which differ, empty if the client is only on one side
+    pub fields: Vec<&'static str>,
+}
+
+
+/// Load client accounts from a snapshot, or from a CSV or JSON accounts output
+pub fn load_accounts(path: &str) -> Result<BTreeMap<ClientId, AccountRecord>, Error> {
+    let text = std::fs::read_to_string(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+
+    // Snapshots are the only JSON objects, account outputs are arrays or CSV
+    let records = if text.trim_start().starts_with('{') {
+        Store::load_snapshot(path)?.clients().map(AccountRecord::from).collect()
+    } else {
+        output::read_accounts(path)?
+    };
+    Ok(records.into_iter().map(|record| (record.client, record)).collect())
+}
+
+
+impl Tolerance {
+    /// Check whether the two amounts are the same within the tolerance
+    pub fn same(&self, left: Decimal, right: Decimal) -> bool {
+        let (left, right) = match self.decimal_places {
+            Some(places) => (left.round_dp(places), right.round_dp(places)),
+            None => (left, right),
+        };
+        (left - right).abs() <= self.absolute
+    }
+}
+
+
+/// Compare the accounts on both sides, returning the clients which differ ordered by ID
+pub fn diff(
+    left: &BTreeMap<ClientId, AccountRecord>, right: &BTreeMap<ClientId, AccountRecord>, tolerance: Tolerance,
+) -> Vec<ClientDiff> {
+    let mut clients: Vec<ClientId> = left.keys().chain(right.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+
+    clients.into_iter().filter_map(|client| {
+        let (left, right) = (left.get(&client), right.get(&client));
+        let fields = match (left, right) {
+            (Some(left), Some(right)) => {
+                let amounts = [
+                    ("available", left.available, right.available),
+                    ("held", left.held, right.held),
+                    ("total", left.total, right.total),
+                ];
+                let mut fields: Vec<&'static str> = amounts.iter()
+                    .filter(|(_, left, right)| !tolerance.same(*left, *right))
+                    .map(|(name, _, _)| *name)
+                    .collect();
+                if left.locked != right.locked { fields.push("locked"); }
+                if fields.is_empty() { return None; }
+                fields
+            },
+            _ => Vec::new(),
+        };
+        Some(ClientDiff { client, left: left.cloned(), right: right.cloned(), fields })
+    }).collect()
+}
+
+
+/// Write the differences, one line per client for people or a JSON array for other programs
+pub fn write_diffs(diffs: &[ClientDiff], format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+    match format {
+        OutputFormat::Csv => {
+            for diff in diffs {
+                match (&diff.left, &diff.right) {
+                    (Some(left), Some(right)) => {
+                        let changes: Vec<String> = diff.fields.iter().map(|field| match *field {
+                            "available" => format!("available {} -> {}", left.available, right.available),
+                            "held" => format!("held {} -> {}", left.held, right.held),
+                            "total" => format!("total {} -> {}", left.total, right.total),
+                            _ => format!("locked {} -> {}", left.locked, right.locked),
+                        }).collect();
+                        writeln!(out, "client {}: {}", diff.client, changes.join(", "))?;
+                    },
+                    (Some(_), None) => writeln!(out, "client {}: only in left", diff.client)?,
+                    _ => writeln!(out, "client {}: only in right", diff.client)?,
+                }
+            }
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, diffs)?;
+            writeln!(out)?;
+        },
+    }
+    out.flush()
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::id::num_id;
+
+    use super::*;
+
+    fn account(client: u64, available: &str, held: &str, locked: bool) -> (ClientId, AccountRecord) {
+        let client = num_id(client);
+        let (available, held) = (Decimal::from_str(available).unwrap(), Decimal::from_str(held).unwrap());
+        (client, AccountRecord {
+            client, available, held, total: available + held, locked, shortfall: Decimal::ZERO,
+            name: None, status: None, kyc_tier: None,
+        })
+    }
+
+    #[test]
+    fn identical() {
+        let left = BTreeMap::from([account(1, "10", "0", false)]);
+        let right = BTreeMap::from([account(1, "10.0000", "0", false)]);
+        assert!(diff(&left, &right, Tolerance::default()).is_empty());
+    }
+
+    #[test]
+    fn changed_fields() {
+        let left = BTreeMap::from([account(1, "10", "5", false), account(2, "1", "0", false)]);
+        let right = BTreeMap::from([account(1, "15", "0", true), account(3, "1", "0", false)]);
+        let diffs = diff(&left, &right, Tolerance::default());
+
+        assert_eq!(diffs.len(), 3);
+        assert_eq!(diffs[0].fields, vec!["available", "held", "locked"]);
+        assert!(diffs[1].right.is_none());
+        assert!(diffs[2].left.is_none());
+    }
+
+    #[test]
+    fn tolerance() {
+        let left = BTreeMap::from([account(1, "10.00004", "0", false)]);
+        let right = BTreeMap::from([account(1, "10.00001", "0", false)]);
+        assert_eq!(diff(&left, &right, Tolerance::default()).len(), 1);
+
+        let absolute = Tolerance { absolute: Decimal::from_str("0.0001").unwrap(), decimal_places: None };
+        assert!(diff(&left, &right, absolute).is_empty());
+
+        let rounded = Tolerance { absolute: Decimal::ZERO, decimal_places: Some(4) };
+        assert!(diff(&left, &right, rounded).is_empty());
+    }
+
+}
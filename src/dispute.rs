@@ -0,0 +1,158 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::ClientId, transaction::TransactionId};
+
+/// ID of a dispute, a `u32` under every ID feature as disputes left without one are numbered in order by the store
+pub type DisputeId = u32;
+
+
+/// Category of the complaint behind a dispute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum ReasonCode {
+    /// The cardholder didn't make or authorise the transaction
+    Fraud,
+    /// Goods or services were paid for but never received
+    NotReceived,
+    /// Goods or services received differ from what was paid for
+    NotAsDescribed,
+    /// The same payment was taken more than once
+    Duplicate,
+    /// A cancelled order or subscription was still charged
+    Cancelled,
+    /// Any reason not covered above
+    Other,
+}
+
+impl ReasonCode {
+    /// Name of the reason as used in the `reason` column
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReasonCode::Fraud => "fraud",
+            ReasonCode::NotReceived => "not_received",
+            ReasonCode::NotAsDescribed => "not_as_described",
+            ReasonCode::Duplicate => "duplicate",
+            ReasonCode::Cancelled => "cancelled",
+            ReasonCode::Other => "other",
+        }
+    }
+
+    /// Reason with the given name as used in the `reason` column
+    pub fn from_name(name: &str) -> Option<ReasonCode> {
+        match name {
+            "fraud" => Some(ReasonCode::Fraud),
+            "not_received" => Some(ReasonCode::NotReceived),
+            "not_as_described" => Some(ReasonCode::NotAsDescribed),
+            "duplicate" => Some(ReasonCode::Duplicate),
+            "cancelled" => Some(ReasonCode::Cancelled),
+            "other" => Some(ReasonCode::Other),
+            _ => None,
+        }
+    }
+}
+
+/// Progress of a dispute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum DisputeStatus {
+    /// Funds are held until the dispute is resolved or charged back
+    Open,
+    /// Held funds were released back to the client
+    Resolved,
+    /// Held funds were removed from the account
+    ChargedBack,
+    /// The chargeback was overturned by a representment or chargeback reversal and the funds given back
+    Reversed,
+}
+
+impl DisputeStatus {
+    /// Name of the status as used in reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisputeStatus::Open => "open",
+            DisputeStatus::Resolved => "resolved",
+            DisputeStatus::ChargedBack => "charged_back",
+            DisputeStatus::Reversed => "reversed",
+        }
+    }
+}
+
+
+/// Dispute over part or all of a deposit or withdrawal, referenced by the resolve or chargeback ending it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Dispute {
+    id: DisputeId,
+    /// Transaction disputed, none for the funds an account was opened with held
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tx: Option<TransactionId>,
+    client: ClientId,
+    amount: Decimal,
+    /// Part of the amount which couldn't be held as the client had already spent it
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    shortfall: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<ReasonCode>,
+    opened_at: DateTime<Utc>,
+    /// Time of the resolve or chargeback ending the dispute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    closed_at: Option<DateTime<Utc>>,
+    /// Time of the representment or chargeback reversal overturning the chargeback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reversed_at: Option<DateTime<Utc>>,
+    status: DisputeStatus,
+}
+
+impl Dispute {
+    // GETTERS
+    pub fn id(&self) -> DisputeId { self.id }
+    pub fn tx(&self) -> Option<TransactionId> { self.tx }
+    pub fn client_id(&self) -> ClientId { self.client }
+    pub fn amount(&self) -> Decimal { self.amount }
+    pub fn shortfall(&self) -> Decimal { self.shortfall }
+    pub fn reason(&self) -> Option<ReasonCode> { self.reason }
+    pub fn opened_at(&self) -> DateTime<Utc> { self.opened_at }
+    pub fn closed_at(&self) -> Option<DateTime<Utc>> { self.closed_at }
+    pub fn reversed_at(&self) -> Option<DateTime<Utc>> { self.reversed_at }
+    pub fn status(&self) -> DisputeStatus { self.status }
+
+    /// Open a dispute over an amount of a transaction
+    pub fn new(id: DisputeId, tx: TransactionId, client: ClientId, amount: Decimal, opened_at: DateTime<Utc>) -> Dispute {
+        Dispute { id, tx: Some(tx), client, amount, shortfall: Decimal::ZERO, reason: None, opened_at, closed_at: None,
+            reversed_at: None, status: DisputeStatus::Open }
+    }
+
+    /// Open a dispute over the funds an account was opened with held, carried over from the legacy system
+    pub fn opening(id: DisputeId, client: ClientId, amount: Decimal, opened_at: DateTime<Utc>) -> Dispute {
+        Dispute { id, tx: None, client, amount, shortfall: Decimal::ZERO, reason: None, opened_at, closed_at: None,
+            reversed_at: None, status: DisputeStatus::Open }
+    }
+
+    /// Set the reason the dispute was raised for
+    pub fn with_reason(mut self, reason: Option<ReasonCode>) -> Dispute {
+        self.reason = reason;
+        self
+    }
+
+    /// Note how much of the amount couldn't be held
+    pub fn with_shortfall(mut self, shortfall: Decimal) -> Dispute {
+        self.shortfall = shortfall;
+        self
+    }
+
+    /// Part of the amount which is held
+    pub fn held(&self) -> Decimal { self.amount - self.shortfall }
+
+    /// End the dispute once it is resolved or charged back
+    pub fn close(&mut self, status: DisputeStatus, at: DateTime<Utc>) {
+        self.status = status;
+        self.closed_at = Some(at);
+    }
+
+    /// Overturn the chargeback which ended the dispute
+    pub fn reverse(&mut self, at: DateTime<Utc>) {
+        self.status = DisputeStatus::Reversed;
+        self.reversed_at = Some(at);
+    }
+}
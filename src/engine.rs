@@ -0,0 +1,606 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::PoisonError;
+use std::time::Instant;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+
+use crate::{
+    config::Rules, dispute::Dispute, events::{Event, Observer}, metrics::SharedMetrics, policy::{Decision, Policy},
+    store::{IdRanges, Store}, transaction::{BatchId, Transaction, TransactionError, TransactionId, TransactionKind},
+};
+
+
+/// Limits on how long disputes, resolves and chargebacks referencing a transaction which hasn't arrived yet are held
+/// back, buffering is disabled unless at least one limit is set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferPolicy {
+    /// Number of rows processed after a reference for which it is still held
+    pub max_rows: Option<u64>,
+    /// Seconds between the timestamps of a reference and the latest row for which it is still held
+    pub max_age_seconds: Option<u64>,
+}
+
+impl BufferPolicy {
+    /// Whether unresolved references should be buffered at all
+    pub fn enabled(&self) -> bool {
+        self.max_rows.is_some() || self.max_age_seconds.is_some()
+    }
+}
+
+
+/// What happened to a transaction handed to the engine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// Applied to the account, or ignored for the reason given
+    Executed(Result<(), TransactionError>),
+    /// Held back until the transaction it references arrives
+    Buffered,
+    /// Held back until the rest of its batch arrives, it is settled along with the batch
+    Batched,
+}
+
+/// Buffered or batched transaction which has since been applied, ignored or given up on as an orphan
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settled {
+    pub transaction: Transaction,
+    pub result: Result<(), TransactionError>,
+}
+
+/// Transaction a policy flagged for review, it was executed all the same
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flag {
+    pub transaction: Transaction,
+    pub reason: &'static str,
+}
+
+/// Events of an execution, published once the batch it's part of is kept
+type Deferred = (TransactionKind, Result<(), TransactionError>, Vec<Event>);
+
+/// Reference waiting for its transaction, along with the row it arrived on
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pending {
+    row: u64,
+    transaction: Transaction,
+}
+
+
+/// Executes transactions against a store under a set of rules, holding back references which arrive early
+pub struct Engine {
+    store: Store,
+    rules: Rules,
+    buffer: BufferPolicy,
+    /// Consulted around every execution, in order
+    policies: Vec<Box<dyn Policy>>,
+    /// Notified of every account change, in order of registration
+    observers: Vec<Box<dyn Observer>>,
+    /// Where rows, results and latencies are recorded, if anywhere
+    metrics: Option<SharedMetrics>,
+    /// Number of rows handed to the engine
+    rows: u64,
+    /// Buffered references by the ID of the transaction they are waiting for, in the order they arrived
+    pending: HashMap<TransactionId, Vec<Pending>>,
+    /// Row, timestamp and target of every buffered reference, oldest first
+    expiry: VecDeque<(u64, DateTime<Utc>, TransactionId)>,
+    /// Rows of the batch being read, applied together once the batch is over
+    batch: Vec<Transaction>,
+    /// Events of the batch being applied, which are only published if it's kept
+    deferred: Option<Vec<Deferred>>,
+    /// Batches which were applied or rolled back, rows of them arriving later are rejected
+    closed: IdRanges<BatchId>,
+    /// Buffered and batched transactions settled since they were last taken
+    settled: Vec<Settled>,
+    /// Transactions flagged for review since they were last taken
+    flagged: Vec<Flag>,
+}
+
+impl Engine {
+    /// Create an engine processing transactions into the given store
+    pub fn new(store: Store, rules: Rules, buffer: BufferPolicy) -> Engine {
+        Engine {
+            store, rules, buffer, policies: Vec::new(), observers: Vec::new(), metrics: None,
+            rows: 0,
+            pending: HashMap::new(), expiry: VecDeque::new(), batch: Vec::new(), deferred: None, closed: IdRanges::default(),
+            settled: Vec::new(), flagged: Vec::new(),
+        }
+    }
+
+    /// Consult the policies around every execution, in the order given
+    pub fn with_policies(mut self, policies: Vec<Box<dyn Policy>>) -> Engine {
+        self.policies = policies;
+        self
+    }
+
+    /// Record rows, results and latencies into the metrics
+    pub fn with_metrics(mut self, metrics: SharedMetrics) -> Engine {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Register a handler receiving every event from now on
+    pub fn subscribe(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    // GETTERS
+    pub fn store(&self) -> &Store { &self.store }
+    pub fn rules(&self) -> &Rules { &self.rules }
+
+    /// Stop processing and hand back the store
+    pub fn into_store(self) -> Store { self.store }
+
+    /// Number of references currently waiting for their transaction
+    pub fn pending(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    /// Execute the transaction, or hold it back if it references a transaction which hasn't arrived yet or is part of
+    /// a batch
+    pub fn process(&mut self, mut transaction: Transaction) -> Outcome {
+        let started = Instant::now();
+        self.rows += 1;
+        self.expire(Some(transaction.timestamp()));
+
+        // A batch is over as soon as a row which isn't part of it arrives
+        if self.batch.first().is_some_and(|first| first.batch() != transaction.batch()) { self.apply_batch(); }
+
+        let outcome = if transaction.batch().is_some_and(|batch| self.closed.contains(batch)) {
+            // The rows of a batch have to be consecutive, a late one can't join a batch which is already settled
+            tracing::info!(tx = %transaction.id(), client = %transaction.client_id(), kind = transaction.kind().name(),
+                    batch = transaction.batch(), "row of a closed batch");
+            let result = Err(TransactionError::BatchClosed);
+            let locked = self.store.get_client(transaction.client_id()).is_some_and(|client| client.locked());
+            self.emit(&transaction, result, None, locked);
+            Outcome::Executed(result)
+        } else if transaction.batch().is_some() {
+            self.batch.push(transaction);
+            Outcome::Batched
+        } else if self.buffer.enabled() && self.waits(&transaction) {
+            self.pending.entry(transaction.id()).or_default().push(Pending { row: self.rows, transaction });
+            self.expiry.push_back((self.rows, transaction.timestamp(), transaction.id()));
+            tracing::debug!(tx = %transaction.id(), client = %transaction.client_id(), kind = transaction.kind().name(),
+                    "waiting for referenced transaction");
+            Outcome::Buffered
+        } else {
+            let result = self.execute(&mut transaction);
+            if matches!(transaction.kind(), TransactionKind::Deposit | TransactionKind::Withdrawal) {
+                self.release(transaction.id());
+            }
+            Outcome::Executed(result)
+        };
+
+        if let Some(metrics) = &self.metrics {
+            metrics.lock().unwrap_or_else(PoisonError::into_inner)
+                .record_row(transaction.kind(), started.elapsed(), self.store.transaction_count());
+        }
+        outcome
+    }
+
+    /// Apply the batch which is still open and give up on every reference still waiting, reporting them as orphans
+    pub fn finish(&mut self) {
+        self.apply_batch();
+        self.expire(None);
+    }
+
+    /// Take the buffered and batched transactions which were applied, ignored or orphaned since the last call
+    pub fn take_settled(&mut self) -> Vec<Settled> {
+        std::mem::take(&mut self.settled)
+    }
+
+    /// Flush every observer, reporting the first error any of them ran into
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.observers.iter_mut().map(|observer| observer.flush()).fold(Ok(()), Result::and)
+    }
+
+    /// Take the transactions flagged for review since the last call
+    pub fn take_flagged(&mut self) -> Vec<Flag> {
+        std::mem::take(&mut self.flagged)
+    }
+
+    /// Execute the transaction unless a policy rejects it, flagging it if a policy asks for a review
+    fn execute(&mut self, transaction: &mut Transaction) -> Result<(), TransactionError> {
+        for policy in &mut self.policies {
+            match policy.before(transaction, &self.store) {
+                Decision::Accept => {},
+                Decision::Reject(reason) => {
+                    tracing::info!(tx = %transaction.id(), client = %transaction.client_id(), kind = transaction.kind().name(),
+                            reason, "transaction rejected by policy");
+                    let result = Err(TransactionError::Rejected(reason));
+                    self.emit(transaction, result, None, false);
+                    return result;
+                },
+                Decision::Review(reason) => {
+                    tracing::info!(tx = %transaction.id(), client = %transaction.client_id(), kind = transaction.kind().name(),
+                            reason, "transaction flagged for review");
+                    self.flagged.push(Flag { transaction: *transaction, reason });
+                },
+            }
+        }
+
+        // Lock state has to be looked up before the transaction changes it
+        let was_locked = self.store.get_client(transaction.client_id()).is_some_and(|client| client.locked());
+
+        let result = transaction.exec_with(&mut self.store, &self.rules);
+        let dispute = transaction.dispute_id().and_then(|id| self.store.get_dispute(id)).copied();
+        for policy in &mut self.policies {
+            // The transaction is already applied, so there's nothing left to reject
+            if let Decision::Review(reason) | Decision::Reject(reason) = policy.after(transaction, result, &self.store) {
+                self.flagged.push(Flag { transaction: *transaction, reason });
+            }
+        }
+        self.emit(transaction, result, dispute, was_locked);
+        result
+    }
+
+    /// Execute the rows of the batch together under a savepoint, rolling all of them back and rejecting the whole batch
+    /// if any of them fails
+    fn apply_batch(&mut self) {
+        if self.batch.is_empty() { return; }
+        let legs = std::mem::take(&mut self.batch);
+        let batch = legs[0].batch().unwrap_or_default();
+        let _span = tracing::debug_span!("batch", batch, legs = legs.len()).entered();
+        self.closed.insert(batch);
+
+        // Policies keep state across transactions too, which has to be rolled back along with the store
+        self.store.savepoint();
+        self.policies.iter_mut().for_each(|policy| policy.savepoint());
+        let flagged = self.flagged.len();
+        self.deferred = Some(Vec::new());
+        let mut failed = false;
+        let mut settled = Vec::with_capacity(legs.len());
+        for mut transaction in legs {
+            // Rows after a failing one aren't executed at all
+            let result = if failed { Err(TransactionError::BatchFailed) } else { self.execute(&mut transaction) };
+            failed |= result.is_err();
+            settled.push(Settled { transaction, result });
+        }
+        let deferred = self.deferred.take().unwrap_or_default();
+
+        if failed {
+            self.store.rollback();
+            self.policies.iter_mut().for_each(|policy| policy.rollback());
+            self.flagged.truncate(flagged);
+            tracing::info!("batch rolled back");
+            for leg in &mut settled {
+                if leg.result.is_ok() { leg.result = Err(TransactionError::BatchFailed); }
+                let (transaction, reason) = (leg.transaction, leg.result.err().map(|err| err.reason()).unwrap_or_default());
+                self.publish(transaction.kind(), leg.result, &[Event::Rejected {
+                    client: transaction.client_id(), tx: transaction.id(), kind: transaction.kind().name(), reason,
+                    timestamp: transaction.timestamp(),
+                }]);
+            }
+            self.settled.append(&mut settled);
+        } else {
+            self.store.release_savepoint();
+            self.policies.iter_mut().for_each(|policy| policy.release_savepoint());
+            for (kind, result, events) in &deferred { self.publish(*kind, *result, events); }
+            let released: Vec<TransactionId> = settled.iter()
+                .filter(|leg| matches!(leg.transaction.kind(), TransactionKind::Deposit | TransactionKind::Withdrawal))
+                .map(|leg| leg.transaction.id())
+                .collect();
+            self.settled.append(&mut settled);
+            released.into_iter().for_each(|id| self.release(id));
+        }
+    }
+
+    /// Notify the observers and metrics of what executing the transaction did to the account
+    fn emit(
+        &mut self, transaction: &Transaction, result: Result<(), TransactionError>, dispute: Option<Dispute>,
+        was_locked: bool,
+    ) {
+        if self.observers.is_empty() && self.metrics.is_none() { return; }
+        let (client, tx, timestamp) = (transaction.client_id(), transaction.id(), transaction.timestamp());
+        let amount = transaction.amount();
+        let (dispute, disputed) = dispute.map(|dispute| (dispute.id(), dispute.amount())).unwrap_or_default();
+
+        let mut events = Vec::with_capacity(2);
+        match result {
+            Err(err) => events.push(Event::Rejected {
+                client, tx, kind: transaction.kind().name(), reason: err.reason(), timestamp,
+            }),
+            Ok(()) => match transaction.kind() {
+                TransactionKind::Deposit => events.push(Event::Deposited { client, tx, amount, timestamp }),
+                TransactionKind::Withdrawal => events.push(Event::Withdrawn { client, tx, amount, timestamp }),
+                TransactionKind::Dispute => events.push(Event::Disputed { client, tx, dispute, amount: disputed, timestamp }),
+                TransactionKind::Resolve => events.push(Event::Resolved { client, tx, dispute, amount: disputed, timestamp }),
+                TransactionKind::Chargeback =>
+                    events.push(Event::ChargedBack { client, tx, dispute, amount: disputed, timestamp }),
+                TransactionKind::CreditLimit => {},
+                TransactionKind::Representment | TransactionKind::ChargebackReversal =>
+                    events.push(Event::ChargebackReversed { client, tx, dispute, amount: disputed, timestamp }),
+                TransactionKind::Reversal => events.push(Event::Reversed { client, tx, amount, timestamp }),
+            },
+        }
+        let locked = self.store.get_client(client).is_some_and(|client| client.locked());
+        if !was_locked && locked {
+            events.push(Event::Locked { client, tx, timestamp });
+        } else if was_locked && !locked {
+            events.push(Event::Unlocked { client, tx, timestamp });
+        }
+
+        match &mut self.deferred {
+            Some(deferred) => deferred.push((transaction.kind(), result, events)),
+            None => self.publish(transaction.kind(), result, &events),
+        }
+    }
+
+    /// Hand the result and events of an execution to the metrics and observers
+    fn publish(&mut self, kind: TransactionKind, result: Result<(), TransactionError>, events: &[Event]) {
+        if let Some(metrics) = &self.metrics {
+            let mut metrics = metrics.lock().unwrap_or_else(PoisonError::into_inner);
+            metrics.record_result(kind, result);
+            events.iter().for_each(|event| metrics.record_event(event));
+        }
+        for event in events {
+            self.observers.iter_mut().for_each(|observer| observer.notify(event));
+        }
+    }
+
+    /// Whether the transaction has to wait for the transaction it references
+    fn waits(&self, transaction: &Transaction) -> bool {
+        match transaction.kind() {
+            // References queue up behind earlier ones to the same transaction so they apply in order
+            TransactionKind::Dispute | TransactionKind::Resolve | TransactionKind::Chargeback
+                | TransactionKind::Representment | TransactionKind::ChargebackReversal | TransactionKind::Reversal =>
+                // Transactions of clients which aren't accepted will never arrive
+                self.store.accepts_client(transaction.client_id()) && (self.pending.contains_key(&transaction.id())
+                    || self.store.get_transaction(transaction.id()) == Err(TransactionError::UnknownTransaction)),
+            _ => false,
+        }
+    }
+
+    /// Apply the references waiting for a transaction which just arrived
+    fn release(&mut self, id: TransactionId) {
+        for Pending { mut transaction, .. } in self.pending.remove(&id).unwrap_or_default() {
+            let result = self.execute(&mut transaction);
+            self.settled.push(Settled { transaction, result });
+        }
+    }
+
+    /// Orphan references which waited longer than the buffer policy allows, or all of them if there's no current time
+    fn expire(&mut self, now: Option<DateTime<Utc>>) {
+        let max_age = self.buffer.max_age_seconds.map(|seconds| TimeDelta::seconds(seconds as i64));
+        while let Some(&(row, timestamp, id)) = self.expiry.front() {
+            let expired = match now {
+                None => true,
+                Some(now) => self.buffer.max_rows.is_some_and(|max| self.rows - row > max)
+                    || max_age.is_some_and(|max| now - timestamp > max),
+            };
+            if !expired { break; }
+            self.expiry.pop_front();
+
+            // The reference may have been released already
+            let Some(waiting) = self.pending.get_mut(&id) else { continue };
+            let orphan = waiting.iter().position(|pending| pending.row == row).map(|index| waiting.remove(index));
+            if waiting.is_empty() { self.pending.remove(&id); }
+            if let Some(Pending { transaction, .. }) = orphan {
+                tracing::info!(tx = %transaction.id(), client = %transaction.client_id(), kind = transaction.kind().name(),
+                        "referenced transaction never arrived");
+                let result = Err(TransactionError::Orphaned);
+                self.emit(&transaction, result, None, false);
+                self.settled.push(Settled { transaction, result });
+            }
+        }
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use chrono::TimeZone;
+    use rust_decimal::Decimal;
+
+    use crate::id::num_id;
+
+    use super::*;
+
+    fn engine(buffer: BufferPolicy) -> Engine {
+        Engine::new(Store::new(), Rules::default(), buffer)
+    }
+
+    fn transaction(kind: TransactionKind, id: u64, amount: i64) -> Transaction {
+        Transaction::new(kind, num_id(1), num_id(id), Decimal::from(amount))
+    }
+
+    #[test]
+    fn disabled_buffer() {
+        let mut engine = engine(BufferPolicy::default());
+        let outcome = engine.process(transaction(TransactionKind::Dispute, 1, 0));
+        assert_eq!(outcome, Outcome::Executed(Err(TransactionError::UnknownTransaction)));
+    }
+
+    #[test]
+    fn early_references_apply_in_order() {
+        let mut engine = engine(BufferPolicy { max_rows: Some(10), max_age_seconds: None });
+        assert_eq!(engine.process(transaction(TransactionKind::Dispute, 1, 0)), Outcome::Buffered);
+        assert_eq!(engine.process(transaction(TransactionKind::Chargeback, 1, 0)), Outcome::Buffered);
+        assert_eq!(engine.pending(), 2);
+
+        assert_eq!(engine.process(transaction(TransactionKind::Deposit, 1, 100)), Outcome::Executed(Ok(())));
+        let settled = engine.take_settled();
+        assert_eq!(settled.len(), 2);
+        assert!(settled.iter().all(|settled| settled.result.is_ok()));
+        assert_eq!(engine.pending(), 0);
+
+        let client = engine.store().get_client(num_id(1)).unwrap();
+        assert_eq!(client.total(), Decimal::ZERO);
+        assert!(client.locked());
+    }
+
+    #[test]
+    fn orphans_by_rows() {
+        let mut engine = engine(BufferPolicy { max_rows: Some(2), max_age_seconds: None });
+        engine.process(transaction(TransactionKind::Dispute, 9, 0));
+        engine.process(transaction(TransactionKind::Deposit, 1, 100));
+        engine.process(transaction(TransactionKind::Deposit, 2, 100));
+        assert!(engine.take_settled().is_empty());
+
+        engine.process(transaction(TransactionKind::Deposit, 3, 100));
+        let settled = engine.take_settled();
+        assert_eq!(settled.len(), 1);
+        assert_eq!(settled[0].result, Err(TransactionError::Orphaned));
+        assert_eq!(engine.pending(), 0);
+    }
+
+    #[test]
+    fn orphans_by_age_and_finish() {
+        let mut engine = engine(BufferPolicy { max_rows: None, max_age_seconds: Some(60) });
+        let start = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        engine.process(transaction(TransactionKind::Dispute, 8, 0).with_timestamp(start));
+        engine.process(transaction(TransactionKind::Dispute, 9, 0).with_timestamp(start + TimeDelta::seconds(30)));
+        engine.process(transaction(TransactionKind::Deposit, 1, 100).with_timestamp(start + TimeDelta::seconds(61)));
+        let settled = engine.take_settled();
+        assert_eq!(settled.len(), 1);
+        assert_eq!(settled[0].transaction.id(), num_id::<TransactionId>(8));
+
+        engine.finish();
+        let settled = engine.take_settled();
+        assert_eq!(settled.len(), 1);
+        assert_eq!(settled[0].transaction.id(), num_id::<TransactionId>(9));
+    }
+
+    #[test]
+    fn policies() {
+        let policies = crate::policy::build_policies(&crate::config::Config::parse("test.toml", concat!(
+            "[[policies]]\ntype = \"max_amount\"\nmax = 100\n",
+            "[[policies]]\ntype = \"max_amount\"\nmax = 50\naction = \"review\"\n",
+        )).unwrap().policies);
+        let mut engine = engine(BufferPolicy::default()).with_policies(policies);
+
+        let rejected = engine.process(transaction(TransactionKind::Deposit, 1, 150));
+        assert_eq!(rejected, Outcome::Executed(Err(TransactionError::Rejected("max_amount"))));
+        assert!(engine.take_flagged().is_empty());
+
+        assert_eq!(engine.process(transaction(TransactionKind::Deposit, 2, 75)), Outcome::Executed(Ok(())));
+        let flagged = engine.take_flagged();
+        assert_eq!(flagged.len(), 1);
+        assert_eq!((flagged[0].transaction.id(), flagged[0].reason), (num_id(2), "max_amount"));
+        assert_eq!(engine.store().get_client(num_id(1)).unwrap().available(), Decimal::from(75));
+    }
+
+    #[test]
+    fn events_in_order() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = engine(BufferPolicy::default());
+        let received = Rc::clone(&events);
+        engine.subscribe(Box::new(move |event: &Event| received.borrow_mut().push(*event)));
+
+        engine.process(transaction(TransactionKind::Deposit, 1, 100));
+        engine.process(transaction(TransactionKind::Withdrawal, 2, 500));
+        engine.process(transaction(TransactionKind::Dispute, 1, 0));
+        engine.process(transaction(TransactionKind::Chargeback, 1, 0));
+
+        let names: Vec<String> = events.borrow().iter()
+            .map(|event| serde_json::to_value(event).unwrap()["event"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["deposited", "rejected", "disputed", "charged_back", "locked"]);
+        assert!(matches!(events.borrow()[3], Event::ChargedBack { amount, .. } if amount == Decimal::from(100)));
+    }
+
+    #[test]
+    fn reversal_events() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let rules = Rules { reversal_unlocks: true, ..Rules::default() };
+        let mut engine = Engine::new(Store::new(), rules, BufferPolicy::default());
+        let received = Rc::clone(&events);
+        engine.subscribe(Box::new(move |event: &Event| received.borrow_mut().push(*event)));
+
+        engine.process(transaction(TransactionKind::Deposit, 1, 100));
+        engine.process(transaction(TransactionKind::Dispute, 1, 0));
+        engine.process(transaction(TransactionKind::Chargeback, 1, 0));
+        engine.process(transaction(TransactionKind::Representment, 1, 0));
+
+        let names: Vec<String> = events.borrow().iter()
+            .map(|event| serde_json::to_value(event).unwrap()["event"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["deposited", "disputed", "charged_back", "locked", "chargeback_reversed", "unlocked"]);
+        assert!(matches!(events.borrow()[4], Event::ChargebackReversed { dispute: 0, amount, .. } if amount == Decimal::from(100)));
+    }
+
+    #[test]
+    fn batches() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = engine(BufferPolicy::default());
+        let received = Rc::clone(&events);
+        engine.subscribe(Box::new(move |event: &Event| received.borrow_mut().push(*event)));
+
+        engine.process(transaction(TransactionKind::Deposit, 1, 100));
+        assert_eq!(engine.process(transaction(TransactionKind::Withdrawal, 2, 10).with_batch(1)), Outcome::Batched);
+        engine.process(transaction(TransactionKind::Withdrawal, 3, 80).with_batch(1));
+        assert!(engine.take_settled().is_empty());
+        // The fee goes through but the payout can't, so neither is applied
+        engine.process(transaction(TransactionKind::Withdrawal, 4, 1).with_batch(2));
+        engine.process(transaction(TransactionKind::Withdrawal, 5, 50).with_batch(2));
+        engine.process(transaction(TransactionKind::Withdrawal, 6, 1).with_batch(2));
+        engine.finish();
+
+        let results: Vec<_> = engine.take_settled().iter().map(|settled| (settled.transaction.id(), settled.result)).collect();
+        assert_eq!(results, vec![
+            (num_id(2), Ok(())), (num_id(3), Ok(())), (num_id(4), Err(TransactionError::BatchFailed)),
+            (num_id(5), Err(TransactionError::InsufficientFunds)), (num_id(6), Err(TransactionError::BatchFailed)),
+        ]);
+        assert_eq!(engine.store().get_client(num_id(1)).unwrap().available(), Decimal::from(10));
+        assert_eq!(engine.store().get_transaction(num_id(4)), Err(TransactionError::UnknownTransaction));
+
+        let names: Vec<String> = events.borrow().iter()
+            .map(|event| serde_json::to_value(event).unwrap()["event"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["deposited", "withdrawn", "withdrawn", "rejected", "rejected", "rejected"]);
+    }
+
+    #[test]
+    fn rolled_back_batch_policies() {
+        let policies = crate::policy::build_policies(&crate::config::Config::parse("test.toml", concat!(
+            "[[policies]]\ntype = \"velocity\"\nwindow_seconds = 60\nmax_count = 1\nkinds = [\"withdrawal\"]\n",
+            "[[policies]]\ntype = \"max_amount\"\nmax = 5\nkinds = [\"withdrawal\"]\naction = \"review\"\n",
+        )).unwrap().policies);
+        let mut engine = engine(BufferPolicy::default()).with_policies(policies);
+
+        engine.process(transaction(TransactionKind::Deposit, 1, 100));
+        // The second withdrawal breaks the velocity limit, so the first one is rolled back along with its flag
+        engine.process(transaction(TransactionKind::Withdrawal, 2, 10).with_batch(1));
+        engine.process(transaction(TransactionKind::Withdrawal, 3, 10).with_batch(1));
+        assert_eq!(engine.process(transaction(TransactionKind::Deposit, 4, 1)), Outcome::Executed(Ok(())));
+        assert!(engine.take_flagged().is_empty());
+
+        assert_eq!(engine.process(transaction(TransactionKind::Withdrawal, 5, 10)), Outcome::Executed(Ok(())));
+        let flagged = engine.take_flagged();
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].transaction.id(), num_id::<TransactionId>(5));
+        assert_eq!(engine.store().get_client(num_id(1)).unwrap().available(), Decimal::from(91));
+    }
+
+    #[test]
+    fn batch_closed() {
+        let mut engine = engine(BufferPolicy::default());
+        engine.process(transaction(TransactionKind::Deposit, 1, 100));
+        engine.process(transaction(TransactionKind::Withdrawal, 2, 10).with_batch(1));
+        engine.process(transaction(TransactionKind::Withdrawal, 3, 10).with_batch(2));
+        let late = engine.process(transaction(TransactionKind::Withdrawal, 4, 10).with_batch(1));
+        assert_eq!(late, Outcome::Executed(Err(TransactionError::BatchClosed)));
+        engine.finish();
+
+        assert_eq!(engine.take_settled().len(), 2);
+        assert_eq!(engine.store().get_client(num_id(1)).unwrap().available(), Decimal::from(80));
+    }
+
+    #[test]
+    fn reversal_event() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = engine(BufferPolicy::default());
+        let received = Rc::clone(&events);
+        engine.subscribe(Box::new(move |event: &Event| received.borrow_mut().push(*event)));
+
+        engine.process(transaction(TransactionKind::Deposit, 1, 100));
+        assert_eq!(engine.process(transaction(TransactionKind::Reversal, 1, 0)), Outcome::Executed(Ok(())));
+        assert!(matches!(events.borrow()[1],
+                Event::Reversed { tx, amount, .. } if tx == num_id::<TransactionId>(1) && amount == Decimal::from(100)));
+    }
+
+}
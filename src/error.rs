@@ -0,0 +1,59 @@
+use std::fmt;
+use std::process::ExitCode;
+
+
+/// Errors which stop a command from completing, each maps onto a process exit code
+#[derive(Debug)]
+pub enum Error {
+    /// Input, output or snapshot file couldn't be opened, read or written
+    Io { path: String, source: std::io::Error },
+    /// A row of the input couldn't be parsed into a transaction
+    Parse { path: String, line: Option<u64>, message: String },
+    /// A snapshot file couldn't be read or written
+    Snapshot { path: String, message: String },
+    /// The configuration file is missing, malformed or contains invalid values
+    Config { path: String, message: String },
+    /// The command line doesn't describe something which can be run
+    Usage(String),
+    /// The command completed but found problems with the data it was given
+    Rejected(String),
+}
+
+
+impl Error {
+    /// Exit code reported to the shell, following the BSD `sysexits.h` conventions
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Error::Io { source, .. } if source.kind() == std::io::ErrorKind::NotFound => ExitCode::from(66),
+            Error::Io { .. } => ExitCode::from(74),
+            Error::Parse { .. } | Error::Snapshot { .. } => ExitCode::from(65),
+            Error::Config { .. } => ExitCode::from(78),
+            Error::Usage(_) => ExitCode::from(64),
+            Error::Rejected(_) => ExitCode::from(1),
+        }
+    }
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path, source),
+            Error::Parse { path, line: Some(line), message } => write!(f, "{}:{}: {}", path, line, message),
+            Error::Parse { path, line: None, message } => write!(f, "{}: {}", path, message),
+            Error::Snapshot { path, message } => write!(f, "{}: invalid snapshot: {}", path, message),
+            Error::Config { path, message } => write!(f, "{}: invalid configuration: {}", path, message),
+            Error::Usage(message) | Error::Rejected(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
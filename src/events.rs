@@ -0,0 +1,112 @@
+use std::io::{self, Write};
+
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{client::ClientId, dispute::DisputeId, transaction::TransactionId};
+
+
+/// Change to a client account, emitted by the engine as transactions are executed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag="event", rename_all="snake_case")]
+pub enum Event {
+    Deposited { client: ClientId, tx: TransactionId, amount: Decimal, timestamp: DateTime<Utc> },
+    Withdrawn { client: ClientId, tx: TransactionId, amount: Decimal, timestamp: DateTime<Utc> },
+    /// Disputed funds of the transaction were moved into held
+    Disputed { client: ClientId, tx: TransactionId, dispute: DisputeId, amount: Decimal, timestamp: DateTime<Utc> },
+    /// Funds held in the dispute were released back to available
+    Resolved { client: ClientId, tx: TransactionId, dispute: DisputeId, amount: Decimal, timestamp: DateTime<Utc> },
+    /// Funds held in the dispute were removed from the account
+    ChargedBack { client: ClientId, tx: TransactionId, dispute: DisputeId, amount: Decimal, timestamp: DateTime<Utc> },
+    /// Funds removed by the dispute's chargeback were given back
+    ChargebackReversed {
+        client: ClientId, tx: TransactionId, dispute: DisputeId, amount: Decimal, timestamp: DateTime<Utc>,
+    },
+    /// The deposit or withdrawal was undone by a reversal
+    Reversed { client: ClientId, tx: TransactionId, amount: Decimal, timestamp: DateTime<Utc> },
+    /// The account was locked by the transaction
+    Locked { client: ClientId, tx: TransactionId, timestamp: DateTime<Utc> },
+    /// The account was unlocked by the transaction
+    Unlocked { client: ClientId, tx: TransactionId, timestamp: DateTime<Utc> },
+    /// The transaction was ignored for the given reason
+    Rejected { client: ClientId, tx: TransactionId, kind: &'static str, reason: &'static str, timestamp: DateTime<Utc> },
+}
+
+/// Handler registered with the engine, receiving every event synchronously in the order they happen
+pub trait Observer {
+    fn notify(&mut self, event: &Event);
+
+    /// Flush anything buffered, reporting errors the handler ran into along the way
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&Event)> Observer for F {
+    fn notify(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+
+/// Observer writing every event as a line of JSON
+pub struct NdjsonWriter<W: Write> {
+    out: W,
+    /// First error writing the events, nothing more is written after it
+    error: Option<io::Error>,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    /// Create an observer writing events into the given output
+    pub fn new(out: W) -> NdjsonWriter<W> {
+        NdjsonWriter { out, error: None }
+    }
+}
+
+impl<W: Write> Observer for NdjsonWriter<W> {
+    fn notify(&mut self, event: &Event) {
+        if self.error.is_some() { return; }
+        let result = serde_json::to_writer(&mut self.out, event).map_err(io::Error::from)
+            .and_then(|_| writeln!(self.out));
+        if let Err(err) = result { self.error = Some(err); }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.out.flush(),
+        }
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use crate::id::num_id;
+
+    use super::*;
+
+    #[test]
+    fn ndjson() {
+        let timestamp = DateTime::from_timestamp(1662033600, 0).unwrap();
+        let mut out = Vec::new();
+        let mut writer = NdjsonWriter::new(&mut out);
+        writer.notify(&Event::Deposited { client: num_id(1), tx: num_id(2), amount: Decimal::from(10), timestamp });
+        writer.notify(&Event::Locked { client: num_id(1), tx: num_id(3), timestamp });
+        writer.flush().unwrap();
+
+        // IDs are written as numbers or strings depending on their type
+        let id = |number| serde_json::to_string(&num_id::<TransactionId>(number)).unwrap();
+        let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines, vec![
+            format!(
+                r#"{{"event":"deposited","client":{},"tx":{},"amount":"10","timestamp":"2022-09-01T12:00:00Z"}}"#, id(1), id(2),
+            ),
+            format!(r#"{{"event":"locked","client":{},"tx":{},"timestamp":"2022-09-01T12:00:00Z"}}"#, id(1), id(3)),
+        ]);
+    }
+
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+use std::hash::Hash;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+
+/// Longest string ID in bytes
+pub const MAX_STR_ID_LEN: usize = 31;
+
+
+/// Identifier of clients and transactions, implemented for the integer widths and for short strings so the ID types
+/// can be chosen with the `u64-ids` and `string-ids` features
+pub trait Id:
+    Copy + Eq + Ord + Hash + fmt::Debug + fmt::Display + FromStr + Serialize + for<'de> Deserialize<'de> + Send + Sync
+    + 'static
+{
+    /// Next ID up, used to store runs of consecutive IDs compactly, `None` if there is none
+    fn successor(&self) -> Option<Self>;
+}
+
+macro_rules! integer_id {
+    ($($int:ty),*) => {$(
+        impl Id for $int {
+            fn successor(&self) -> Option<Self> { self.checked_add(1) }
+        }
+    )*};
+}
+
+integer_id!(u16, u32, u64);
+
+/// ID spelled as the number, so tests and benchmarks run whichever type IDs are built with, panics if the number
+/// doesn't fit the ID type
+#[doc(hidden)]
+pub fn num_id<I: Id>(number: u64) -> I {
+    number.to_string().parse().unwrap_or_else(|_| panic!("ID {} doesn't fit", number))
+}
+
+
+/// Reason a string can't be used as an ID
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdError(String);
+
+impl fmt::Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for IdError {}
+
+
+/// String ID of up to `MAX_STR_ID_LEN` bytes kept inline, so IDs stay `Copy`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StrId {
+    /// Zero padded, so comparing the bytes compares the strings
+    bytes: [u8; MAX_STR_ID_LEN],
+    len: u8,
+}
+
+impl StrId {
+    pub fn as_str(&self) -> &str {
+        // Only ever built from a `str`
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl Id for StrId {
+    fn successor(&self) -> Option<Self> { None }
+}
+
+impl FromStr for StrId {
+    type Err = IdError;
+
+    fn from_str(value: &str) -> Result<StrId, IdError> {
+        if value.is_empty() { return Err(IdError("ID is empty".to_string())); }
+        if value.len() > MAX_STR_ID_LEN {
+            return Err(IdError(format!("ID `{}` is longer than {} bytes", value, MAX_STR_ID_LEN)));
+        }
+        if value.contains('\0') { return Err(IdError("ID contains a NUL character".to_string())); }
+        let mut bytes = [0; MAX_STR_ID_LEN];
+        bytes[..value.len()].copy_from_slice(value.as_bytes());
+        Ok(StrId { bytes, len: value.len() as u8 })
+    }
+}
+
+impl fmt::Display for StrId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Padding is honoured as the tables line up their columns with it
+        f.pad(self.as_str())
+    }
+}
+
+impl fmt::Debug for StrId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl Serialize for StrId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for StrId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<StrId, D::Error> {
+        struct StrIdVisitor;
+
+        impl<'de> de::Visitor<'de> for StrIdVisitor {
+            type Value = StrId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string ID")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<StrId, E> {
+                value.trim().parse().map_err(E::custom)
+            }
+
+            // TOML hands its numbers over as numbers even when asked for a string, such as in lists of clients
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<StrId, E> { self.visit_str(&value.to_string()) }
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<StrId, E> { self.visit_str(&value.to_string()) }
+        }
+
+        // Asking for a string keeps CSV columns as they were written, rather than parsing `007` as the number 7
+        deserializer.deserialize_str(StrIdVisitor)
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_ids() {
+        let id: StrId = "acct-0042".parse().unwrap();
+        assert_eq!(id.as_str(), "acct-0042");
+        assert_eq!(format!("{: <12}|", id), "acct-0042   |");
+        assert!("acct-0041".parse::<StrId>().unwrap() < id);
+        assert!("acct".parse::<StrId>().unwrap() < id);
+        assert_eq!(id.successor(), None);
+
+        assert!("".parse::<StrId>().is_err());
+        assert!("x".repeat(MAX_STR_ID_LEN + 1).parse::<StrId>().is_err());
+        assert!("x".repeat(MAX_STR_ID_LEN).parse::<StrId>().is_ok());
+    }
+
+    #[test]
+    fn string_id_serde() {
+        let id: StrId = serde_json::from_str("\"partner-7\"").unwrap();
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"partner-7\"");
+        let (padded,): (StrId,) = csv::ReaderBuilder::new().has_headers(false).from_reader("007\n".as_bytes())
+            .deserialize().next().unwrap().unwrap();
+        assert_eq!(padded.as_str(), "007");
+        let numeric: std::collections::HashMap<String, StrId> = toml::from_str("client = 5000000000").unwrap();
+        assert_eq!(numeric["client"].as_str(), "5000000000");
+    }
+
+    #[test]
+    fn integer_successors() {
+        assert_eq!(7u64.successor(), Some(8));
+        assert_eq!(u16::MAX.successor(), None);
+    }
+
+}
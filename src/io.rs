@@ -1,41 +1,965 @@
-use crate::transaction::{Transaction};
-use csv::{Reader, DeserializeRecordsIntoIter};
+use crate::{
+    transaction::{OpeningBalance, Reversal, Transaction, TransactionId, TransactionRecord}, store::{self, Store}, client::{Client, ClientId, ClientProfile, ClientStatus},
+    dispute::{Dispute, DisputeStatus}, error::Error,
+};
+use csv::{Reader, ReaderBuilder, StringRecord, Trim};
+use rust_decimal::Decimal;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Read, Write};
+use std::rc::Rc;
+
+
+/// Path used on the command line to read from stdin or write to stdout
+pub const STDIO_PATH: &str = "-";
+
+
+/// Supported encodings of the transactions input
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all="lowercase")]
+pub enum InputFormat {
+    /// `type,client,tx,amount` rows with a header
+    #[default]
+    Csv,
+    /// One JSON object per line with the same fields as the CSV columns
+    Ndjson,
+}
+
+/// Supported encodings of the client account output
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all="lowercase")]
+pub enum OutputFormat {
+    /// Padded CSV table of client accounts
+    #[default]
+    Csv,
+    /// JSON array of client accounts
+    Json,
+}
+
+
+/// Client account state in the shape it is written out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountRecord {
+    pub client: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    /// Disputed funds which couldn't be held as they were already spent, only written when there are any
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub shortfall: Decimal,
+    /// Master data of the client, only written when a client master file was loaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<ClientStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kyc_tier: Option<u8>,
+}
+
+impl From<&Client> for AccountRecord {
+    fn from(client: &Client) -> AccountRecord {
+        AccountRecord {
+            client: client.id(), available: client.available(), held: client.held(),
+            total: client.total(), locked: client.locked(), shortfall: client.shortfall(),
+            name: client.profile().map(|profile| profile.name.clone()),
+            status: client.profile().map(|profile| profile.status),
+            kyc_tier: client.profile().map(|profile| profile.kyc_tier),
+        }
+    }
+}
+
+
+/// Master data of a client as read from the client master file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct ClientMasterRecord {
+    client: ClientId,
+    #[serde(flatten)]
+    profile: ClientProfile,
+}
+
+
+/// Agreed overdraft of a client as read from the credit limits file
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+struct CreditLimitRecord {
+    client: ClientId,
+    credit_limit: Decimal,
+}
+
+/// Overdrawn client account in the shape it is reported
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OverdraftRecord {
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub available: Decimal,
+    pub credit_limit: Decimal,
+    /// How much of the credit limit withdrawals have drawn
+    pub overdraft: Decimal,
+}
+
+
+/// Money a client owes in the shape it is reported
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExposureRecord {
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub available: Decimal,
+    pub held: Decimal,
+    /// Disputed funds which couldn't be held as they were already spent
+    pub shortfall: Decimal,
+    /// Debt below zero available funds plus the shortfall
+    pub exposure: Decimal,
+}
+
+/// Disputes sharing a reason or client added up in the shape they are reported
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DisputeSummary {
+    pub disputes: u64,
+    pub amount: Decimal,
+    pub open: u64,
+    pub resolved: u64,
+    pub charged_back: u64,
+    pub reversed: u64,
+    /// Amount taken back by chargebacks which weren't reversed
+    pub charged_back_amount: Decimal,
+}
+
+impl DisputeSummary {
+    /// Count a dispute in the summary
+    fn add(&mut self, dispute: &Dispute) {
+        self.disputes += 1;
+        self.amount += dispute.amount();
+        match dispute.status() {
+            DisputeStatus::Open => self.open += 1,
+            DisputeStatus::Resolved => self.resolved += 1,
+            DisputeStatus::ChargedBack => {
+                self.charged_back += 1;
+                self.charged_back_amount += dispute.amount();
+            },
+            DisputeStatus::Reversed => self.reversed += 1,
+        }
+    }
+}
+
+
+/// Stored transaction in the shape it is listed in a client's history
+#[derive(Debug, Serialize)]
+struct HistoryEntry<'a> {
+    tx: TransactionId,
+    #[serde(flatten)]
+    record: &'a TransactionRecord,
+}
+
+/// Entry of a client's history, the opening balance of a migrated account coming before its transactions and the
+/// reversals of them after
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum HistoryItem<'a> {
+    Opening(&'a OpeningBalance),
+    Transaction(HistoryEntry<'a>),
+    Reversal(&'a Reversal),
+}
+
+
+/// Byte offsets of the line feeds read but not yet passed by the parser, used to work out the line a record starts on
+/// as the CSV parser doesn't count blank lines or CRLF line endings
+#[derive(Debug, Default)]
+struct LineIndex {
+    newlines: VecDeque<u64>,
+    /// Line feeds before the last record looked up
+    passed: u64,
+}
+
+impl LineIndex {
+    /// Line of the record the parser places at the given offset, offsets have to be looked up in increasing order
+    fn line_at(&mut self, byte: u64) -> u64 {
+        // Record positions include the blank lines skipped before the record
+        let mut start = byte;
+        while let Some(&newline) = self.newlines.front() {
+            if newline > start { break; }
+            if newline == start { start += 1; }
+            self.newlines.pop_front();
+            self.passed += 1;
+        }
+        self.passed + 1
+    }
+}
+
+
+/// Reader dropping the carriage returns of CRLF line endings while noting where every line ends
+struct LineReader<R: Read> {
+    inner: BufReader<R>,
+    lines: Rc<RefCell<LineIndex>>,
+    /// Bytes handed out so far
+    offset: u64,
+    /// A carriage return ended the last buffer, it's only dropped if the next byte is a line feed
+    pending_cr: bool,
+}
+
+impl<R: Read> LineReader<R> {
+    fn new(inner: R, lines: Rc<RefCell<LineIndex>>) -> LineReader<R> {
+        LineReader { inner: BufReader::new(inner), lines, offset: 0, pending_cr: false }
+    }
+}
+
+impl<R: Read> Read for LineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() { return Ok(0); }
+        loop {
+            let data = self.inner.fill_buf()?;
+            let mut written = 0;
+            if self.pending_cr && data.first() != Some(&b'\n') {
+                buf[0] = b'\r';
+                written = 1;
+            }
+            self.pending_cr = false;
+
+            let mut consumed = 0;
+            let mut lines = self.lines.borrow_mut();
+            while consumed < data.len() && written < buf.len() {
+                let byte = data[consumed];
+                consumed += 1;
+                match byte {
+                    b'\r' => match data.get(consumed) {
+                        Some(b'\n') => continue,
+                        Some(_) => {},
+                        None => {
+                            self.pending_cr = true;
+                            break;
+                        },
+                    },
+                    b'\n' => lines.newlines.push_back(self.offset + written as u64),
+                    _ => {},
+                }
+                buf[written] = byte;
+                written += 1;
+            }
+            self.inner.consume(consumed);
+            self.offset += written as u64;
+
+            // Only a held back carriage return was read, there's more to come before the end of the input
+            if written > 0 || !self.pending_cr { return Ok(written); }
+        }
+    }
+}
+
+
+/// Rows iterator for each of the supported input formats
+enum Rows {
+    Csv {
+        reader: Reader<Box<dyn Read>>,
+        headers: Option<StringRecord>,
+        record: StringRecord,
+        lines: Rc<RefCell<LineIndex>>,
+    },
+    Ndjson { lines: Lines<BufReader<Box<dyn Read>>> },
+}
 
 
 pub struct TransactionReader<'a> {
-    /// Path to the CSV file to read
+    /// Path to the file to read
     path: &'a str,
-    /// CSV Iterator for fetching rows to be parsed
-    iter: DeserializeRecordsIntoIter<File, Transaction>,
+    /// Iterator for fetching rows to be parsed
+    rows: Rows,
+    /// Line of the input the last returned row started on
+    line: u64,
 }
 
 
 impl TransactionReader<'_> {
     /// Create a reader object to store the transaction record iterator created for given csv file path
-    pub fn new(path: &str) -> TransactionReader<'_> {
-        let reader = Reader::from_path(path)
-            .unwrap_or_else( |_err| panic!("Couldn't find file: {}", path)); 
+    pub fn new(path: &str) -> Result<TransactionReader<'_>, Error> {
+        TransactionReader::with_format(path, InputFormat::Csv)
+    }
+
+    /// Create a reader for a file in the given format, the path `-` reads from stdin
+    pub fn with_format(path: &str, format: InputFormat) -> Result<TransactionReader<'_>, Error> {
+        let source: Box<dyn Read> = if path == STDIO_PATH {
+            Box::new(io::stdin())
+        } else {
+            Box::new(File::open(path).map_err(|source| Error::Io { path: path.to_string(), source })?)
+        };
 
-        let iter: DeserializeRecordsIntoIter<File, Transaction> = reader.into_deserialize();
-        TransactionReader { path, iter }
+        let rows = match format {
+            InputFormat::Csv => {
+                let lines = Rc::new(RefCell::new(LineIndex::default()));
+                let source: Box<dyn Read> = Box::new(LineReader::new(source, Rc::clone(&lines)));
+                Rows::Csv {
+                    reader: ReaderBuilder::new().trim(Trim::All).from_reader(source),
+                    headers: None,
+                    record: StringRecord::new(),
+                    lines,
+                }
+            },
+            InputFormat::Ndjson => Rows::Ndjson { lines: BufReader::new(source).lines() },
+        };
+        Ok(TransactionReader { path, rows, line: 0 })
+    }
+
+    /// Line of the input the most recently returned row (or parse error) started on
+    pub fn line(&self) -> u64 { self.line }
+
+    /// Build a parse error for the current line
+    fn parse_error(&self, message: String) -> Error {
+        Error::Parse { path: self.path.to_string(), line: Some(self.line), message }
+    }
+}
+
+
+impl Iterator for TransactionReader<'_> {
+    type Item = Result<Transaction, Error>;
+
+    /// Fetch the next transaction record from the input
+    fn next(&mut self) -> Option<Result<Transaction, Error>> {
+        let _span = tracing::trace_span!("read", path = self.path).entered();
+        let row = self.next_row();
+        match &row {
+            Some(Ok(transaction)) => tracing::trace!(
+                line = self.line, tx = %transaction.id(), client = %transaction.client_id(), kind = transaction.kind().name(),
+                "row read"),
+            Some(Err(err)) => tracing::debug!(line = self.line, error = %err, "row couldn't be read"),
+            None => tracing::trace!("end of input"),
+        }
+        row
     }
 }
 
 
-impl Iterator for TransactionReader<'_> {    
-    type Item = Transaction;
+impl TransactionReader<'_> {
+    /// Read and parse the next row in the format of the input
+    fn next_row(&mut self) -> Option<Result<Transaction, Error>> {
+        match &mut self.rows {
+            Rows::Csv { reader, headers, record, lines } => {
+                if headers.is_none() {
+                    match reader.headers() {
+                        Ok(row) => *headers = Some(row.clone()),
+                        Err(err) => return Some(Err(self.parse_error(err.to_string()))),
+                    }
+                }
 
-    /// Fetch the next transaction record from the CSV
-    fn next(&mut self) -> Option<Transaction> {
-        // Get the next Transaction record from the reader
-        match self.iter.next() {
-            None => None,
-            Option::Some(result) => {
-                let transaction = result
-                    .unwrap_or_else(|err| panic!("Couldn't parse transaction from file ({}): {}", self.path, err));
-                return Some(transaction);
+                // Get the next Transaction record from the reader
+                let result = reader.read_record(record);
+                let position = match &result {
+                    Ok(true) => record.position(),
+                    Ok(false) => None,
+                    Err(err) => err.position(),
+                };
+                if let Some(position) = position { self.line = lines.borrow_mut().line_at(position.byte()); }
+                match result {
+                    Ok(false) => None,
+                    Ok(true) => Some(record.deserialize(headers.as_ref()).map_err(|err| self.parse_error(err.to_string()))),
+                    Err(err) => Some(Err(self.parse_error(err.to_string()))),
+                }
+            },
+            Rows::Ndjson { lines } => loop {
+                self.line += 1;
+                let text = match lines.next()? {
+                    Ok(text) => text,
+                    Err(source) => return Some(Err(Error::Io { path: self.path.to_string(), source })),
+                };
+                // Blank lines carry no record
+                if text.trim().is_empty() { continue; }
+                return Some(serde_json::from_str(&text).map_err(|err| self.parse_error(err.to_string())));
             },
         }
     }
 }
+
+
+/// Deserialize every row of a CSV input along with the line it starts on, counted like `TransactionReader` counts them
+fn read_csv<T: DeserializeOwned>(path: &str, source: impl Read) -> Result<Vec<(u64, T)>, Error> {
+    let parse_error = |line, message: String| Error::Parse { path: path.to_string(), line, message };
+    let lines = Rc::new(RefCell::new(LineIndex::default()));
+    let line_at = |position: Option<&csv::Position>| position.map(|position| lines.borrow_mut().line_at(position.byte()));
+
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(LineReader::new(source, Rc::clone(&lines)));
+    let headers = reader.headers().map_err(|err| parse_error(line_at(err.position()), err.to_string()))?.clone();
+    reader.records()
+        .map(|record| {
+            let record = record.map_err(|err| parse_error(line_at(err.position()), err.to_string()))?;
+            let line = line_at(record.position());
+            let row = record.deserialize(Some(&headers)).map_err(|err| parse_error(line, err.to_string()))?;
+            Ok((line.unwrap_or_default(), row))
+        })
+        .collect()
+}
+
+
+/// Read client accounts previously written by `write_clients`, in either the CSV or the JSON format
+pub fn read_accounts(path: &str) -> Result<Vec<AccountRecord>, Error> {
+    Ok(read_account_lines(path)?.into_iter().map(|(_, record)| record).collect())
+}
+
+/// Read client accounts along with the line each of them starts on, which is only known for CSV
+fn read_account_lines(path: &str) -> Result<Vec<(Option<u64>, AccountRecord)>, Error> {
+    let text = std::fs::read_to_string(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+
+    // A JSON array can't be mistaken for the start of a CSV header
+    if text.trim_start().starts_with('[') {
+        let records: Vec<AccountRecord> = serde_json::from_str(&text).map_err(|err| Error::Parse {
+            path: path.to_string(), line: Some(err.line() as u64), message: err.to_string(),
+        })?;
+        return Ok(records.into_iter().map(|record| (None, record)).collect());
+    }
+    Ok(read_csv(path, text.as_bytes())?.into_iter().map(|(line, record)| (Some(line), record)).collect())
+}
+
+
+/// Read the credit limit of each client from a `client,credit_limit` CSV file
+pub fn read_credit_limits(path: &str) -> Result<Vec<(ClientId, Decimal)>, Error> {
+    let file = File::open(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+    read_csv(path, file)?.into_iter().map(|(line, record): (u64, CreditLimitRecord)| {
+        if record.credit_limit < Decimal::ZERO {
+            return Err(Error::Parse {
+                path: path.to_string(), line: Some(line),
+                message: format!("negative credit limit for client {}", record.client),
+            });
+        }
+        Ok((record.client, record.credit_limit))
+    }).collect()
+}
+
+
+/// Read the balances to open accounts with from an accounts file in the shape of the output, along with the line each
+/// of them starts on for CSV, checking every total adds up, nothing held is negative and no client is listed twice
+pub fn read_opening_balances(path: &str) -> Result<Vec<(Option<u64>, AccountRecord)>, Error> {
+    let records = read_account_lines(path)?;
+    let mut seen = HashSet::new();
+    for (line, record) in &records {
+        let invalid = |message: String| Error::Parse { path: path.to_string(), line: *line, message };
+        if record.total != record.available + record.held {
+            return Err(invalid(format!(
+                "total {} of client {} isn't available {} plus held {}", record.total, record.client, record.available,
+                record.held,
+            )));
+        }
+        // Held funds are kept in a dispute, which can't be over a negative amount
+        if record.held < Decimal::ZERO {
+            return Err(invalid(format!("negative held funds for client {}", record.client)));
+        }
+        if !seen.insert(record.client) { return Err(invalid(format!("client {} is listed twice", record.client))); }
+    }
+    Ok(records)
+}
+
+
+/// Read the master data of each client from a `client,name,status,kyc_tier` CSV file
+pub fn read_client_master(path: &str) -> Result<Vec<(ClientId, ClientProfile)>, Error> {
+    let file = File::open(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+    let mut seen = HashSet::new();
+    read_csv(path, file)?.into_iter().map(|(line, record): (u64, ClientMasterRecord)| {
+        if !seen.insert(record.client) {
+            return Err(Error::Parse {
+                path: path.to_string(), line: Some(line), message: format!("client {} is listed twice", record.client),
+            });
+        }
+        Ok((record.client, record.profile))
+    }).collect()
+}
+
+
+/// Open the file at the given path for writing, `-` or no path at all writes to stdout
+pub fn open_output(path: Option<&str>) -> Result<Box<dyn Write>, Error> {
+    match path {
+        None | Some(STDIO_PATH) => Ok(Box::new(BufWriter::new(io::stdout()))),
+        Some(path) => {
+            let file = File::create(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+            Ok(Box::new(BufWriter::new(file)))
+        },
+    }
+}
+
+
+/// Write all client accounts in the store in the requested format
+pub fn write_clients(store: &Store, format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+    match format {
+        OutputFormat::Csv => store.write_clients(out)?,
+        OutputFormat::Json => {
+            let records: Vec<AccountRecord> = store.clients().map(AccountRecord::from).collect();
+            serde_json::to_writer_pretty(&mut *out, &records)?;
+            writeln!(out)?;
+        },
+    }
+    out.flush()
+}
+
+
+/// Write the accounts which are overdrawn using their credit limit, ordered by client ID
+pub fn write_overdrawn(store: &Store, format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+    let mut records: Vec<OverdraftRecord> = store.clients()
+        .filter(|client| client.overdrawn())
+        .map(|client| OverdraftRecord {
+            client: client.id(), name: client.profile().map(|profile| profile.name.clone()),
+            available: client.available(), credit_limit: client.credit_limit(), overdraft: client.credit_used(),
+        })
+        .collect();
+    records.sort_by_key(|record| record.client);
+    let names = store.has_profiles();
+
+    match format {
+        OutputFormat::Csv => {
+            write!(out, "{0: <10}", "client")?;
+            if names { write!(out, ", {0: <20}", "name")?; }
+            writeln!(out, ", {0: <10}, {1: <12}, {2: <10}", "available", "credit_limit", "overdraft")?;
+            for record in &records {
+                write!(out, "{0: <10}", record.client)?;
+                if names { store::write_text_column(out, record.name.as_deref().unwrap_or_default(), 20)?; }
+                writeln!(out, ", {0: <10}, {1: <12}, {2: <10}", record.available, record.credit_limit, record.overdraft)?;
+            }
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, &records)?;
+            writeln!(out)?;
+        },
+    }
+    out.flush()
+}
+
+
+/// Write the accounts owing money, through available funds below zero or disputed funds which were already spent,
+/// ordered by client ID
+pub fn write_exposure(store: &Store, format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+    let mut records: Vec<ExposureRecord> = store.clients()
+        .filter(|client| client.available() < Decimal::ZERO || client.shortfall() > Decimal::ZERO)
+        .map(|client| ExposureRecord {
+            client: client.id(), name: client.profile().map(|profile| profile.name.clone()),
+            available: client.available(), held: client.held(), shortfall: client.shortfall(),
+            exposure: (-client.available()).max(Decimal::ZERO) + client.shortfall(),
+        })
+        .collect();
+    records.sort_by_key(|record| record.client);
+    let names = store.has_profiles();
+
+    match format {
+        OutputFormat::Csv => {
+            write!(out, "{0: <10}", "client")?;
+            if names { write!(out, ", {0: <20}", "name")?; }
+            writeln!(out, ", {0: <10}, {1: <10}, {2: <10}, {3: <10}", "available", "held", "shortfall", "exposure")?;
+            for record in &records {
+                write!(out, "{0: <10}", record.client)?;
+                if names { store::write_text_column(out, record.name.as_deref().unwrap_or_default(), 20)?; }
+                writeln!(out, ", {0: <10}, {1: <10}, {2: <10}, {3: <10}",
+                        record.available, record.held, record.shortfall, record.exposure)?;
+            }
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, &records)?;
+            writeln!(out)?;
+        },
+    }
+    out.flush()
+}
+
+
+/// Write every stored dispute ordered by dispute ID
+pub fn write_disputes(store: &Store, format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+    let mut disputes: Vec<&Dispute> = store.disputes().collect();
+    disputes.sort_by_key(|dispute| dispute.id());
+
+    match format {
+        OutputFormat::Csv => {
+            writeln!(out, "{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <10}, {5: <16}, {6: <12}, {7: <25}, {8: <25}, {9: <10}",
+                    "dispute", "tx", "client", "amount", "shortfall", "reason", "status", "opened_at", "closed_at",
+                    "reversed_at")?;
+            for dispute in disputes {
+                let reason = dispute.reason().map(|reason| reason.name()).unwrap_or_default();
+                let closed_at = dispute.closed_at().map(|time| time.to_rfc3339()).unwrap_or_default();
+                let reversed_at = dispute.reversed_at().map(|time| time.to_rfc3339()).unwrap_or_default();
+                // Disputes over held opening balances have no transaction
+                let tx = dispute.tx().map(|tx| tx.to_string()).unwrap_or_default();
+                writeln!(out, "{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <10}, {5: <16}, {6: <12}, {7: <25}, {8: <25}, {9: <10}",
+                        dispute.id(), tx, dispute.client_id(), dispute.amount(), dispute.shortfall(), reason,
+                        dispute.status().name(), dispute.opened_at().to_rfc3339(), closed_at, reversed_at)?;
+            }
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, &disputes)?;
+            writeln!(out)?;
+        },
+    }
+    out.flush()
+}
+
+/// Write the dispute counts and amounts of each reason code, `none` for the disputes raised without one
+pub fn write_disputes_by_reason(store: &Store, format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+    let summaries = summarise_disputes(store, |dispute| dispute.reason().map(|reason| reason.name()).unwrap_or("none"));
+    write_dispute_summaries("reason", &summaries, format, out)
+}
+
+/// Write the dispute counts and amounts of each client with disputes, ordered by client ID
+pub fn write_disputes_by_client(store: &Store, format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+    let summaries = summarise_disputes(store, |dispute| dispute.client_id());
+    write_dispute_summaries("client", &summaries, format, out)
+}
+
+/// Add up the stored disputes by the given key
+fn summarise_disputes<K: Ord>(store: &Store, key: impl Fn(&Dispute) -> K) -> BTreeMap<K, DisputeSummary> {
+    let mut summaries: BTreeMap<K, DisputeSummary> = BTreeMap::new();
+    for dispute in store.disputes() {
+        summaries.entry(key(dispute)).or_default().add(dispute);
+    }
+    summaries
+}
+
+/// Write dispute summaries as a table with the key in the first column, or as a JSON object keyed by it
+fn write_dispute_summaries<K: Display + Serialize>(
+    name: &str, summaries: &BTreeMap<K, DisputeSummary>, format: OutputFormat, out: &mut dyn Write,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Csv => {
+            writeln!(out, "{0: <16}, {1: <10}, {2: <10}, {3: <10}, {4: <10}, {5: <12}, {6: <10}, {7: <10}",
+                    name, "disputes", "amount", "open", "resolved", "charged_back", "reversed", "charged_back_amount")?;
+            for (key, summary) in summaries {
+                writeln!(out, "{0: <16}, {1: <10}, {2: <10}, {3: <10}, {4: <10}, {5: <12}, {6: <10}, {7: <10}",
+                        key.to_string(), summary.disputes, summary.amount, summary.open, summary.resolved,
+                        summary.charged_back, summary.reversed, summary.charged_back_amount)?;
+            }
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, summaries)?;
+            writeln!(out)?;
+        },
+    }
+    out.flush()
+}
+
+
+/// Write the stored transactions of a client ordered by transaction ID, followed by the reversals in the order they
+/// were carried out
+pub fn write_history(store: &Store, client: ClientId, format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+    let mut entries: Vec<HistoryEntry> = store.transactions()
+        .filter(|(_, record)| record.client_id() == client)
+        .map(|(tx, record)| HistoryEntry { tx, record })
+        .collect();
+    entries.sort_by_key(|entry| entry.tx);
+    let mut reversals: Vec<&Reversal> = store.reversals().filter(|reversal| reversal.client_id() == client).collect();
+    reversals.sort_by_key(|reversal| reversal.id());
+    let opening = store.get_opening(client);
+
+    match format {
+        OutputFormat::Csv => {
+            writeln!(out, "{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <12}, {5: <10}",
+                    "type", "client", "tx", "amount", "state", "timestamp")?;
+            if let Some(opening) = opening {
+                writeln!(out, "{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <12}, {5: <10}", "opening_balance",
+                        opening.client_id(), "", opening.total(), "processed", opening.timestamp().to_rfc3339())?;
+            }
+            for entry in &entries {
+                writeln!(out, "{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <12}, {5: <10}", entry.record.kind().name(),
+                        entry.record.client_id(), entry.tx, entry.record.amount(), entry.record.state().name(),
+                        entry.record.timestamp().to_rfc3339())?;
+            }
+            for reversal in &reversals {
+                writeln!(out, "{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <12}, {5: <10}", "reversal",
+                        reversal.client_id(), reversal.tx(), reversal.amount(), "processed",
+                        reversal.timestamp().to_rfc3339())?;
+            }
+        },
+        OutputFormat::Json => {
+            let items: Vec<HistoryItem> = opening.map(HistoryItem::Opening).into_iter()
+                .chain(entries.into_iter().map(HistoryItem::Transaction))
+                .chain(reversals.into_iter().map(HistoryItem::Reversal))
+                .collect();
+            serde_json::to_writer_pretty(&mut *out, &items)?;
+            writeln!(out)?;
+        },
+    }
+    out.flush()
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{dispute::{DisputeStatus, ReasonCode}, id::num_id, transaction::{TransactionError, TransactionKind}};
+
+    use super::*;
+
+    /// ID as it's written to JSON, whichever type IDs are
+    fn json_id(number: u64) -> serde_json::Value {
+        serde_json::to_value(num_id::<TransactionId>(number)).unwrap()
+    }
+
+    #[test]
+    fn json_output() {
+        let mut store = Store::new();
+        store.get_or_create_client(num_id(3)).deposit(Decimal::from_str("1.5").unwrap());
+
+        let mut out = Vec::new();
+        write_clients(&store, OutputFormat::Json, &mut out).unwrap();
+        let records: Vec<AccountRecord> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(records, vec![AccountRecord {
+            client: num_id(3), available: Decimal::from_str("1.5").unwrap(), held: Decimal::ZERO,
+            total: Decimal::from_str("1.5").unwrap(), locked: false, shortfall: Decimal::ZERO,
+            name: None, status: None, kyc_tier: None,
+        }]);
+    }
+
+    #[test]
+    fn read_written_accounts() {
+        let mut store = Store::new();
+        store.get_or_create_client(num_id(3)).deposit(Decimal::from_str("1.5").unwrap());
+        store.get_or_create_client(num_id(4));
+
+        for format in [OutputFormat::Csv, OutputFormat::Json] {
+            let path = std::env::temp_dir().join(format!("transactions-accounts-{:?}-{}", format, std::process::id()));
+            let path = path.to_str().unwrap();
+            let mut out = File::create(path).unwrap();
+            write_clients(&store, format, &mut out).unwrap();
+            let mut records = read_accounts(path).unwrap();
+            std::fs::remove_file(path).unwrap();
+
+            records.sort_by_key(|record| record.client);
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0].available, Decimal::from_str("1.5").unwrap());
+            assert_eq!(records[1].total, Decimal::ZERO);
+        }
+    }
+
+    #[test]
+    fn client_master() {
+        let path = std::env::temp_dir().join(format!("transactions-master-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "client,name,status,kyc_tier\n1,\"Smith, John\",active,2\n2,Ada,suspended,1\n").unwrap();
+        let profiles = read_client_master(path).unwrap();
+        assert_eq!(profiles[0], (num_id(1), ClientProfile {
+            name: "Smith, John".to_string(), status: ClientStatus::Active, kyc_tier: 2,
+        }));
+
+        let mut store = Store::new();
+        store.set_profiles(profiles, true);
+        store.get_or_create_client(num_id(1)).deposit(Decimal::from(5));
+        for format in [OutputFormat::Csv, OutputFormat::Json] {
+            let mut out = File::create(path).unwrap();
+            write_clients(&store, format, &mut out).unwrap();
+            let mut records = read_accounts(path).unwrap();
+            records.sort_by_key(|record| record.client);
+            assert_eq!(records[0].name.as_deref(), Some("Smith, John"));
+            assert_eq!(records[1].status, Some(ClientStatus::Suspended));
+            assert_eq!(records[1].kyc_tier, Some(1));
+        }
+
+        std::fs::write(path, "client,name,status,kyc_tier\n1,Ada,active,1\n1,Ada,closed,1\n").unwrap();
+        let duplicate = read_client_master(path);
+        std::fs::write(path, "client,name,status,kyc_tier\n1,Ada,gone,1\n").unwrap();
+        let invalid = read_client_master(path);
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(duplicate, Err(Error::Parse { .. })));
+        assert!(matches!(invalid, Err(Error::Parse { .. })));
+    }
+
+    #[test]
+    fn opening_balances() {
+        let path = std::env::temp_dir().join(format!("transactions-opening-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "client,available,held,total,locked\n1,80,0,80,false\n2,5,0,5,true\n").unwrap();
+        let records = read_opening_balances(path).unwrap();
+        std::fs::write(path, "client,available,held,total,locked\n1,80,0,90,false\n").unwrap();
+        let mismatch = read_opening_balances(path);
+        std::fs::write(path, "client,available,held,total,locked\n2,1,0,1,false\n1,80,-20,60,false\n").unwrap();
+        let negative = read_opening_balances(path);
+        std::fs::write(path, "client,available,held,total,locked\n1,1,0,1,false\n1,1,0,1,false\n").unwrap();
+        let duplicate = read_opening_balances(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].0, Some(3));
+        assert!(records[1].1.locked);
+        assert!(matches!(mismatch, Err(Error::Parse { line: Some(2), .. })));
+        assert!(matches!(negative, Err(Error::Parse { line: Some(3), .. })));
+        assert!(matches!(duplicate, Err(Error::Parse { line: Some(3), .. })));
+
+        let mut store = Store::new();
+        store.open_account(OpeningBalance::new(num_id(1), Decimal::from(100), Decimal::ZERO, false, Default::default()));
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(7), Decimal::from(10)).exec(&mut store).unwrap();
+        let mut out = Vec::new();
+        write_history(&store, num_id(1), OutputFormat::Json, &mut out).unwrap();
+        let history: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(history[0]["type"], "opening_balance");
+        assert_eq!(history[0]["available"], "100");
+        assert_eq!(history[1]["tx"], json_id(7));
+
+        let mut out = Vec::new();
+        write_history(&store, num_id(1), OutputFormat::Csv, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let rows: Vec<Vec<&str>> = text.lines().skip(1).map(|line| line.split(',').map(str::trim).collect()).collect();
+        assert_eq!(&rows[0][..4], ["opening_balance", "1", "", "100"]);
+        assert_eq!(&rows[1][..4], ["deposit", "1", "7", "10"]);
+    }
+
+    #[test]
+    fn opening_balances_round_trip() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from(100)).exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from(30)).exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Deposit, num_id(2), num_id(2), Decimal::from(5)).exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(2), num_id(2), Decimal::ZERO).exec(&mut store).unwrap();
+        let path = std::env::temp_dir().join(format!("transactions-round-trip-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut out = Vec::new();
+        write_clients(&store, OutputFormat::Csv, &mut out).unwrap();
+        std::fs::write(path, out).unwrap();
+        let records = read_opening_balances(path);
+        std::fs::remove_file(path).unwrap();
+
+        let mut migrated = Store::new();
+        for (_, record) in records.unwrap() {
+            let opening = OpeningBalance::new(record.client, record.available, record.held, record.locked, Default::default());
+            assert!(migrated.open_account(opening));
+        }
+        let client = migrated.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(70), Decimal::from(30)));
+        let id = migrated.get_opening(num_id(1)).unwrap().dispute().unwrap();
+        assert_eq!(migrated.get_dispute(id).unwrap().tx(), None);
+
+        // The held funds are released by a resolve or chargeback naming the dispute over the opening balance
+        let mut unnamed = Transaction::new(TransactionKind::Resolve, num_id(1), num_id(1), Decimal::ZERO);
+        assert_eq!(unnamed.exec(&mut migrated), Err(TransactionError::UnknownTransaction));
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(1), Decimal::ZERO).with_dispute(id)
+            .exec(&mut migrated).unwrap();
+        let client = migrated.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(100), Decimal::ZERO));
+        assert_eq!(migrated.get_dispute(id).unwrap().status(), DisputeStatus::Resolved);
+
+        let id = migrated.get_opening(num_id(2)).unwrap().dispute().unwrap();
+        Transaction::new(TransactionKind::Chargeback, num_id(2), num_id(2), Decimal::ZERO).with_dispute(id)
+            .exec(&mut migrated).unwrap();
+        let client = migrated.get_client(num_id(2)).unwrap();
+        assert_eq!((client.total(), client.locked()), (Decimal::ZERO, true));
+    }
+
+    #[test]
+    fn reversal_history() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(7), Decimal::from(10)).exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Reversal, num_id(1), num_id(7), Decimal::ZERO).exec(&mut store).unwrap();
+
+        let mut out = Vec::new();
+        write_history(&store, num_id(1), OutputFormat::Json, &mut out).unwrap();
+        let history: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(history[0]["state"], "reversed");
+        assert_eq!(history[0]["reversal"], 0);
+        assert_eq!(history[1]["type"], "reversal");
+        assert_eq!((history[1]["id"].as_u64(), &history[1]["tx"]), (Some(0), &json_id(7)));
+
+        let mut out = Vec::new();
+        write_history(&store, num_id(1), OutputFormat::Csv, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let rows: Vec<Vec<&str>> = text.lines().skip(1).map(|line| line.split(',').map(str::trim).collect()).collect();
+        assert_eq!(&rows[1][..4], ["reversal", "1", "7", "10"]);
+    }
+
+    #[test]
+    fn exposure() {
+        let mut store = Store::new();
+        store.get_or_create_client(num_id(1)).dispute_overdrawing(Decimal::from(40));
+        store.get_or_create_client(num_id(2)).deposit(Decimal::from(10));
+        store.get_or_create_client(num_id(2)).dispute_remaining(Decimal::from(25));
+        store.get_or_create_client(num_id(3)).deposit(Decimal::from(10));
+
+        let mut out = Vec::new();
+        write_exposure(&store, OutputFormat::Csv, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let rows: Vec<Vec<&str>> = text.lines().skip(1).map(|line| line.split(',').map(str::trim).collect()).collect();
+        assert_eq!(rows, vec![vec!["1", "-40", "40", "0", "40"], vec!["2", "0", "10", "15", "15"]]);
+    }
+
+    #[test]
+    fn dispute_summaries() {
+        let mut store = Store::new();
+        for (tx, client) in [(1, 1), (2, 1), (3, 2)] {
+            Transaction::new(TransactionKind::Deposit, num_id(client), num_id(tx), Decimal::from(10)).exec(&mut store).unwrap();
+        }
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO).with_reason(ReasonCode::Fraud)
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(1), Decimal::ZERO).exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(2), num_id(3), Decimal::from(4)).with_reason(ReasonCode::Fraud)
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(2), Decimal::ZERO).exec(&mut store).unwrap_err();
+
+        let mut out = Vec::new();
+        write_disputes_by_reason(&store, OutputFormat::Csv, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let rows: Vec<Vec<&str>> = text.lines().skip(1).map(|line| line.split(',').map(str::trim).collect()).collect();
+        assert_eq!(rows, vec![vec!["fraud", "2", "14", "1", "0", "1", "0", "10"]]);
+
+        let mut out = Vec::new();
+        write_disputes_by_client(&store, OutputFormat::Json, &mut out).unwrap();
+        let summaries: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(summaries["1"]["charged_back"], 1);
+        assert_eq!(summaries["2"]["open"], 1);
+    }
+
+    #[test]
+    fn overdrawn_accounts() {
+        let mut store = Store::new();
+        store.set_credit_limits([(num_id(1), Decimal::from(100))]);
+        store.get_or_create_client(num_id(1)).withdraw(Decimal::from(40));
+        store.get_or_create_client(num_id(2)).deposit(Decimal::from(10));
+        // Disputes over spent funds take the available funds below zero without using the credit limit
+        store.get_or_create_client(num_id(3)).dispute_overdrawing(Decimal::from(25));
+
+        let mut out = Vec::new();
+        write_overdrawn(&store, OutputFormat::Json, &mut out).unwrap();
+        let records: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(records.as_array().unwrap().len(), 1);
+        assert_eq!(records[0]["client"], json_id(1));
+        assert_eq!(records[0]["overdraft"], "40");
+
+        let mut out = Vec::new();
+        write_exposure(&store, OutputFormat::Json, &mut out).unwrap();
+        let records: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let clients: Vec<&serde_json::Value> = records.as_array().unwrap().iter().map(|record| &record["client"]).collect();
+        assert_eq!(clients, [&json_id(1), &json_id(3)]);
+    }
+
+    #[test]
+    fn line_numbers() {
+        let mut out = Vec::new();
+        let mut reader = LineReader::new("a\r\nb\rc\r\n\r\n".as_bytes(), Rc::default());
+        // Single byte reads put every carriage return at the end of a buffer
+        let mut byte = [0; 1];
+        while reader.read(&mut byte).unwrap() > 0 { out.push(byte[0]); }
+        assert_eq!(out, b"a\nb\rc\n\n");
+
+        let path = std::env::temp_dir().join(format!("transactions-crlf-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "type,client,tx,amount\r\ndeposit,1,1,10\r\n\r\n\ndeposit,1,2,x\r\n").unwrap();
+        let rows: Vec<Result<Transaction, Error>> = TransactionReader::new(path).unwrap().collect();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert!(matches!(rows[1], Err(Error::Parse { line: Some(5), .. })));
+    }
+
+    #[test]
+    fn reference_file_line_numbers() {
+        let path = std::env::temp_dir().join(format!("transactions-crlf-limits-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "client,credit_limit\r\n1,10\r\n\r\n2,-5\r\n").unwrap();
+        let err = read_credit_limits(path).unwrap_err();
+        std::fs::write(path, "client,name,status,kyc_tier\r\n\r\n1,Ann,active,1\r\n1,Ann,active,1\r\n").unwrap();
+        let duplicate = read_client_master(path).unwrap_err();
+        std::fs::write(path, "client,credit_limit\r\n1,10\r\n\r\n\r\n2,x\r\n").unwrap();
+        let invalid = read_credit_limits(path).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(err, Error::Parse { line: Some(4), .. }));
+        assert!(matches!(duplicate, Error::Parse { line: Some(4), .. }));
+        assert!(matches!(invalid, Error::Parse { line: Some(5), .. }));
+    }
+
+    #[test]
+    fn missing_input() {
+        let err = TransactionReader::new("does-not-exist.csv").err().unwrap();
+        assert!(matches!(err, Error::Io { .. }));
+    }
+
+}
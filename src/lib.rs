@@ -0,0 +1,20 @@
+pub mod cli;
+pub mod error;
+pub mod io;
+pub mod logging;
+pub mod transaction;
+pub mod client;
+pub mod config;
+pub mod diff;
+pub mod dispute;
+pub mod engine;
+pub mod events;
+pub mod id;
+pub mod metrics;
+pub mod policy;
+pub mod store;
+pub mod validate;
+
+
+#[cfg(test)]
+mod tests;
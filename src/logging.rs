@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::error::Error;
+
+
+/// Encodings of the log lines
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all="lowercase")]
+pub enum LogFormat {
+    /// Human readable lines with the fields of the enclosing spans
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Where logs go and how much of them is written, stdout is never used so it only ever carries results
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Level (`error`, `warn`, `info`, `debug`, `trace`) or per-module filter such as `transactions::store=trace`
+    pub level: String,
+    pub format: LogFormat,
+    /// File to append logs to instead of stderr
+    pub file: Option<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig { level: "warn".to_string(), format: LogFormat::default(), file: None }
+    }
+}
+
+
+/// Install the global subscriber writing logs as configured, does nothing if one is already installed
+pub fn init(config: &LogConfig) -> Result<(), Error> {
+    let filter = EnvFilter::try_new(&config.level)
+        .map_err(|err| Error::Usage(format!("invalid log level `{}`: {}", config.level, err)))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    let result = match &config.file {
+        Some(path) => {
+            let file = File::options().create(true).append(true).open(path)
+                .map_err(|source| Error::Io { path: path.clone(), source })?;
+            let builder = builder.with_writer(Mutex::new(file)).with_ansi(false);
+            match config.format {
+                LogFormat::Text => builder.try_init(),
+                LogFormat::Json => builder.json().try_init(),
+            }
+        },
+        None => {
+            let builder = builder.with_writer(std::io::stderr);
+            match config.format {
+                LogFormat::Text => builder.try_init(),
+                LogFormat::Json => builder.json().try_init(),
+            }
+        },
+    };
+    // Only fails if a subscriber was installed already, which is left in place
+    let _ = result;
+    Ok(())
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_level() {
+        let config = LogConfig { level: "transactions=loud".to_string(), ..LogConfig::default() };
+        assert!(matches!(init(&config), Err(Error::Usage(_))));
+    }
+
+}
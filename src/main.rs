@@ -1,36 +1,17 @@
-use std::env;
-use io::TransactionReader;
-use store::Store;
+use std::process::ExitCode;
 
-mod io;
-mod transaction;
-mod client;
-mod store;
+use clap::Parser;
+use transactions::cli::{self, Cli};
 
-fn main() {
-    // Get path to CSV to be parsed
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Expected 1 argument - path to transactions data CSV file")
-    }
-    let data_path = args[1].as_str();
-    
-    // Get iterator for parsing transactions iteratively
-    let reader = TransactionReader::new(data_path);
-
-    // Init storage for client accounts and transactions
-    let mut store = Store::new();
+fn main() -> ExitCode {
+    // Parse command line, printing help or usage errors and exiting as necessary
+    let cli = Cli::parse();
 
-    // Execute each available transaction
-    for mut transaction in reader {
-        transaction.exec(&mut store);
+    match cli::run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            err.exit_code()
+        },
     }
-
-    // Stdout client records in CSV format
-    store.print_clients();
 }
-
-
-#[cfg(test)]
-mod tests;
-
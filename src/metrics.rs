@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    dispute::DisputeStatus, events::Event, io::OutputFormat, store::Store,
+    transaction::{TransactionError, TransactionKind},
+};
+
+
+/// Upper bounds in seconds of the processing latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.01, 0.1, 1.0];
+
+/// Metrics shared between the engine recording them and whatever exposes them
+pub type SharedMetrics = Arc<Mutex<Metrics>>;
+
+
+/// Counters, gauges and histograms describing a run, written in the Prometheus text exposition format
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Rows handed to the engine
+    rows: u64,
+    rows_by_kind: BTreeMap<&'static str, u64>,
+    /// Transactions applied to the accounts by kind
+    succeeded: BTreeMap<&'static str, u64>,
+    /// Transactions ignored by reason
+    failed: BTreeMap<&'static str, u64>,
+    /// Number of rows in each latency bucket, not cumulative
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_count: u64,
+    latency_sum: f64,
+    /// Amounts deposited and withdrawn by the transactions applied
+    deposited: Decimal,
+    withdrawn: Decimal,
+    open_disputes: u64,
+    locked_accounts: u64,
+    /// Accounts locked while recording, however many were unlocked since
+    locks: u64,
+    stored_transactions: u64,
+}
+
+
+/// Overview of a processing run, written once the input is processed
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunSummary {
+    /// Rows handed to the engine by transaction kind
+    pub rows: BTreeMap<&'static str, u64>,
+    /// Transactions applied to the accounts by kind
+    pub succeeded: BTreeMap<&'static str, u64>,
+    /// Transactions ignored by reason
+    pub failed: BTreeMap<&'static str, u64>,
+    pub money_in: Decimal,
+    pub money_out: Decimal,
+    pub open_disputes: u64,
+    pub newly_locked: u64,
+    pub elapsed_seconds: f64,
+    pub rows_per_second: f64,
+}
+
+impl Metrics {
+    /// Start recording with gauges reflecting the current state of the store
+    pub fn new(store: &Store) -> Metrics {
+        Metrics {
+            open_disputes: store.disputes().filter(|dispute| dispute.status() == DisputeStatus::Open).count() as u64,
+            locked_accounts: store.clients().filter(|client| client.locked()).count() as u64,
+            stored_transactions: store.transaction_count() as u64,
+            ..Metrics::default()
+        }
+    }
+
+    /// Start recording into metrics which can be shared with another thread
+    pub fn shared(store: &Store) -> SharedMetrics {
+        Arc::new(Mutex::new(Metrics::new(store)))
+    }
+
+    /// Count a row handed to the engine and how long it took to process
+    pub fn record_row(&mut self, kind: TransactionKind, latency: Duration, stored_transactions: usize) {
+        self.rows += 1;
+        *self.rows_by_kind.entry(kind.name()).or_default() += 1;
+
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.latency_buckets[bucket] += 1;
+        }
+        self.latency_count += 1;
+        self.latency_sum += seconds;
+        self.stored_transactions = stored_transactions as u64;
+    }
+
+    /// Count the result of executing a transaction
+    pub fn record_result(&mut self, kind: TransactionKind, result: Result<(), TransactionError>) {
+        match result {
+            Ok(()) => *self.succeeded.entry(kind.name()).or_default() += 1,
+            Err(err) => *self.failed.entry(err.reason()).or_default() += 1,
+        }
+    }
+
+    /// Track the money moved, open disputes and locked accounts through the events of the engine
+    pub fn record_event(&mut self, event: &Event) {
+        match event {
+            Event::Deposited { amount, .. } => self.deposited += amount,
+            Event::Withdrawn { amount, .. } => self.withdrawn += amount,
+            Event::Disputed { .. } => self.open_disputes += 1,
+            Event::Resolved { .. } | Event::ChargedBack { .. } => self.open_disputes = self.open_disputes.saturating_sub(1),
+            Event::Locked { .. } => {
+                self.locked_accounts += 1;
+                self.locks += 1;
+            },
+            Event::Unlocked { .. } => self.locked_accounts = self.locked_accounts.saturating_sub(1),
+            _ => {},
+        }
+    }
+
+    /// Sum up what was recorded over a run which took the given time
+    pub fn summary(&self, elapsed: Duration) -> RunSummary {
+        let seconds = elapsed.as_secs_f64();
+        RunSummary {
+            rows: self.rows_by_kind.clone(), succeeded: self.succeeded.clone(), failed: self.failed.clone(),
+            money_in: self.deposited, money_out: self.withdrawn,
+            open_disputes: self.open_disputes, newly_locked: self.locks,
+            elapsed_seconds: seconds,
+            rows_per_second: if seconds > 0.0 { self.rows as f64 / seconds } else { 0.0 },
+        }
+    }
+
+    /// Write every metric in the Prometheus text exposition format
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_metric(out, "transactions_rows_read_total", "counter", "Rows handed to the engine")?;
+        writeln!(out, "transactions_rows_read_total {}", self.rows)?;
+
+        write_metric(out, "transactions_rows_total", "counter", "Rows handed to the engine by transaction kind")?;
+        for (kind, count) in &self.rows_by_kind {
+            writeln!(out, "transactions_rows_total{{kind=\"{}\"}} {}", kind, count)?;
+        }
+
+        write_metric(out, "transactions_succeeded_total", "counter", "Transactions applied to the accounts by kind")?;
+        for (kind, count) in &self.succeeded {
+            writeln!(out, "transactions_succeeded_total{{kind=\"{}\"}} {}", kind, count)?;
+        }
+
+        write_metric(out, "transactions_failed_total", "counter", "Transactions ignored by reason")?;
+        for (reason, count) in &self.failed {
+            writeln!(out, "transactions_failed_total{{reason=\"{}\"}} {}", reason, count)?;
+        }
+
+        write_metric(out, "transactions_deposited_amount_total", "counter", "Amount deposited by applied deposits")?;
+        writeln!(out, "transactions_deposited_amount_total {}", self.deposited)?;
+        write_metric(out, "transactions_withdrawn_amount_total", "counter", "Amount withdrawn by applied withdrawals")?;
+        writeln!(out, "transactions_withdrawn_amount_total {}", self.withdrawn)?;
+
+        write_metric(out, "transactions_processing_seconds", "histogram", "Time taken to process a row")?;
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.latency_buckets) {
+            cumulative += count;
+            writeln!(out, "transactions_processing_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative)?;
+        }
+        writeln!(out, "transactions_processing_seconds_bucket{{le=\"+Inf\"}} {}", self.latency_count)?;
+        writeln!(out, "transactions_processing_seconds_sum {}", self.latency_sum)?;
+        writeln!(out, "transactions_processing_seconds_count {}", self.latency_count)?;
+
+        write_metric(out, "transactions_open_disputes", "gauge", "Disputes currently open")?;
+        writeln!(out, "transactions_open_disputes {}", self.open_disputes)?;
+        write_metric(out, "transactions_locked_accounts", "gauge", "Client accounts locked after a chargeback")?;
+        writeln!(out, "transactions_locked_accounts {}", self.locked_accounts)?;
+        write_metric(out, "transactions_locks_total", "counter", "Client accounts locked while processing")?;
+        writeln!(out, "transactions_locks_total {}", self.locks)?;
+        write_metric(out, "transactions_stored", "gauge", "Transactions kept in storage")?;
+        writeln!(out, "transactions_stored {}", self.stored_transactions)?;
+        Ok(())
+    }
+}
+
+impl RunSummary {
+    /// Write the summary as aligned text lines or as JSON
+    pub fn write(&self, format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+        match format {
+            OutputFormat::Csv => self.write_text(out)?,
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut *out, self)?;
+                writeln!(out)?;
+            },
+        }
+        out.flush()
+    }
+
+    /// Write a line per count or amount
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{0: <28} {1}", "rows", self.rows.values().sum::<u64>())?;
+        for (kind, count) in &self.rows {
+            writeln!(out, "{0: <28} {1}", format!("kind {}", kind), count)?;
+        }
+        writeln!(out, "{0: <28} {1}", "succeeded", self.succeeded.values().sum::<u64>())?;
+        for (kind, count) in &self.succeeded {
+            writeln!(out, "{0: <28} {1}", format!("succeeded {}", kind), count)?;
+        }
+        writeln!(out, "{0: <28} {1}", "failed", self.failed.values().sum::<u64>())?;
+        for (reason, count) in &self.failed {
+            writeln!(out, "{0: <28} {1}", format!("failed {}", reason), count)?;
+        }
+        writeln!(out, "{0: <28} {1}", "money in", self.money_in)?;
+        writeln!(out, "{0: <28} {1}", "money out", self.money_out)?;
+        writeln!(out, "{0: <28} {1}", "open disputes", self.open_disputes)?;
+        writeln!(out, "{0: <28} {1}", "newly locked accounts", self.newly_locked)?;
+        writeln!(out, "{0: <28} {1:.3}s", "elapsed", self.elapsed_seconds)?;
+        writeln!(out, "{0: <28} {1:.0} rows/s", "throughput", self.rows_per_second)?;
+        Ok(())
+    }
+}
+
+
+/// Write the help and type lines introducing a metric
+fn write_metric(out: &mut dyn Write, name: &str, kind: &str, help: &str) -> io::Result<()> {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} {}", name, kind)
+}
+
+
+/// Serve the metrics on `/metrics` from a background thread for as long as the process runs, returning the address
+/// it listens on
+pub fn serve(addr: &str, metrics: SharedMetrics) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A client going away mid request is no reason to stop serving the others
+            let _ = respond(stream, &metrics);
+        }
+    });
+    Ok(local)
+}
+
+/// Answer a single HTTP request with the current metrics
+fn respond(mut stream: TcpStream, metrics: &SharedMetrics) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers are read to the blank line ending them so closing the connection doesn't reset it
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() { header.clear(); }
+
+    if request.starts_with("GET /metrics ") {
+        let mut body = Vec::new();
+        metrics.lock().unwrap_or_else(PoisonError::into_inner).write(&mut body)?;
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                Connection: close\r\n\r\n", body.len())?;
+        stream.write_all(&body)
+    } else {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::{id::num_id, transaction::Transaction};
+
+    use super::*;
+
+    fn sample() -> Metrics {
+        let mut metrics = Metrics::default();
+        metrics.record_row(TransactionKind::Deposit, Duration::from_micros(3), 1);
+        metrics.record_result(TransactionKind::Deposit, Ok(()));
+        metrics.record_row(TransactionKind::Withdrawal, Duration::from_millis(2), 2);
+        metrics.record_result(TransactionKind::Withdrawal, Err(TransactionError::InsufficientFunds));
+        let (client, tx, timestamp) = (num_id(1), num_id(1), Default::default());
+        metrics.record_event(&Event::Deposited { client, tx, amount: Decimal::TEN, timestamp });
+        metrics.record_event(&Event::Disputed { client, tx, dispute: 0, amount: Decimal::ONE, timestamp });
+        metrics
+    }
+
+    #[test]
+    fn exposition() {
+        let mut out = Vec::new();
+        sample().write(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        for line in [
+            "transactions_rows_read_total 2",
+            "transactions_rows_total{kind=\"withdrawal\"} 1",
+            "transactions_succeeded_total{kind=\"deposit\"} 1",
+            "transactions_failed_total{reason=\"insufficient_funds\"} 1",
+            "transactions_processing_seconds_bucket{le=\"0.000005\"} 1",
+            "transactions_processing_seconds_bucket{le=\"0.01\"} 2",
+            "transactions_processing_seconds_count 2",
+            "transactions_deposited_amount_total 10",
+            "transactions_withdrawn_amount_total 0",
+            "transactions_open_disputes 1",
+            "transactions_locks_total 0",
+            "transactions_stored 2",
+        ] {
+            assert!(text.lines().any(|written| written == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let mut metrics = sample();
+        metrics.record_event(&Event::Locked { client: num_id(1), tx: num_id(1), timestamp: Default::default() });
+        metrics.record_event(&Event::Unlocked { client: num_id(1), tx: num_id(1), timestamp: Default::default() });
+        let summary = metrics.summary(Duration::from_millis(500));
+        assert_eq!(summary.money_in, Decimal::TEN);
+        assert_eq!(summary.newly_locked, 1);
+        assert_eq!(summary.rows_per_second, 4.0);
+        assert_eq!(summary.failed.get("insufficient_funds"), Some(&1));
+
+        let mut out = Vec::new();
+        summary.write(OutputFormat::Csv, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        for line in ["rows                         2", "succeeded deposit            1", "failed                       1",
+                "open disputes                1", "elapsed                      0.500s"] {
+            assert!(text.lines().any(|written| written == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn endpoint() {
+        let addr = serve("127.0.0.1:0", Arc::new(Mutex::new(sample()))).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("transactions_rows_read_total 2"));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn gauges_from_store() {
+        let mut store = Store::new();
+        for tx in [1, 2] {
+            Transaction::new(TransactionKind::Deposit, num_id(1), num_id(tx), Decimal::TEN).exec(&mut store).unwrap();
+            Transaction::new(TransactionKind::Dispute, num_id(1), num_id(tx), Decimal::ZERO).exec(&mut store).unwrap();
+        }
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(2), Decimal::ZERO).exec(&mut store).unwrap();
+
+        let metrics = Metrics::new(&store);
+        assert_eq!((metrics.open_disputes, metrics.stored_transactions), (1, 2));
+    }
+
+}
@@ -0,0 +1,422 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    client::ClientId, store::Store,
+    transaction::{Transaction, TransactionError, TransactionKind},
+};
+
+
+/// Verdict of a policy on a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Nothing to object to
+    Accept,
+    /// Ignore the transaction for the given reason
+    Reject(&'static str),
+    /// Let the transaction through but flag it for someone to review for the given reason
+    Review(&'static str),
+}
+
+/// Acceptance rule consulted by the engine around the execution of every transaction
+pub trait Policy {
+    /// Consulted before the transaction is executed, a rejection stops it from being executed at all
+    fn before(&mut self, _transaction: &Transaction, _store: &Store) -> Decision {
+        Decision::Accept
+    }
+
+    /// Consulted after the transaction is executed with its result, the transaction has already been applied by then so
+    /// only a review flag has any effect
+    fn after(&mut self, _transaction: &Transaction, _result: Result<(), TransactionError>, _store: &Store) -> Decision {
+        Decision::Accept
+    }
+
+    /// Start keeping track of changes to the state of the policy so they can be rolled back, along with the store
+    fn savepoint(&mut self) {}
+
+    /// Undo every change to the state of the policy since the savepoint
+    fn rollback(&mut self) {}
+
+    /// Keep the changes to the state of the policy since the savepoint
+    fn release_savepoint(&mut self) {}
+}
+
+
+/// What a built-in policy does with a transaction breaking it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum Action {
+    #[default]
+    Reject,
+    Review,
+}
+
+impl Action {
+    /// Decision to hand back for a transaction breaking the policy
+    fn decide(&self, reason: &'static str) -> Decision {
+        match self {
+            Action::Reject => Decision::Reject(reason),
+            Action::Review => Decision::Review(reason),
+        }
+    }
+}
+
+
+/// State a policy keeps for each client, which can be rolled back to a savepoint
+struct ClientState<V> {
+    values: HashMap<ClientId, V>,
+    /// Values changed since the savepoint as they were before their first change, while a savepoint is set
+    savepoint: Option<HashMap<ClientId, Option<V>>>,
+}
+
+impl<V: Clone + Default> ClientState<V> {
+    fn new() -> ClientState<V> {
+        ClientState { values: HashMap::new(), savepoint: None }
+    }
+
+    fn get(&self, client: ClientId) -> Option<&V> {
+        self.values.get(&client)
+    }
+
+    /// State of the client to change, if it has any
+    fn get_mut(&mut self, client: ClientId) -> Option<&mut V> {
+        if !self.values.contains_key(&client) { return None; }
+        Some(self.entry(client))
+    }
+
+    /// State of the client to change, starting from the default if it has none
+    fn entry(&mut self, client: ClientId) -> &mut V {
+        if let Some(savepoint) = &mut self.savepoint {
+            savepoint.entry(client).or_insert_with(|| self.values.get(&client).cloned());
+        }
+        self.values.entry(client).or_default()
+    }
+
+    fn savepoint(&mut self) {
+        self.savepoint = Some(HashMap::new());
+    }
+
+    fn rollback(&mut self) {
+        for (client, value) in self.savepoint.take().unwrap_or_default() {
+            match value {
+                Some(value) => self.values.insert(client, value),
+                None => self.values.remove(&client),
+            };
+        }
+    }
+
+    fn release_savepoint(&mut self) {
+        self.savepoint = None;
+    }
+}
+
+
+/// Built-in policy as configured in a `[[policies]]` entry of the configuration file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag="type", rename_all="snake_case", deny_unknown_fields)]
+pub enum PolicyConfig {
+    /// Limit how many transactions, or how much money, a client can move within a sliding window of time
+    Velocity {
+        window_seconds: u64,
+        max_count: Option<usize>,
+        max_amount: Option<Decimal>,
+        #[serde(default = "money_kinds", deserialize_with = "deserialize_kinds")]
+        kinds: Vec<TransactionKind>,
+        #[serde(default)]
+        action: Action,
+    },
+    /// Limit the amount of a single transaction
+    MaxAmount {
+        max: Decimal,
+        #[serde(default = "money_kinds", deserialize_with = "deserialize_kinds")]
+        kinds: Vec<TransactionKind>,
+        #[serde(default)]
+        action: Action,
+    },
+    /// Stop any transaction on the accounts of the listed clients
+    BlockedClients {
+        #[serde(deserialize_with = "deserialize_clients")]
+        clients: Vec<ClientId>,
+        #[serde(default)]
+        action: Action,
+    },
+    /// Limit how many transactions of a kind, or how much money in them, each client has over the whole run
+    KindLimit {
+        #[serde(deserialize_with = "deserialize_kind")]
+        kind: TransactionKind,
+        max_count: Option<u64>,
+        max_total: Option<Decimal>,
+        #[serde(default)]
+        action: Action,
+    },
+}
+
+/// Kinds of transactions which move money, checked by policies unless configured otherwise
+fn money_kinds() -> Vec<TransactionKind> {
+    vec![TransactionKind::Deposit, TransactionKind::Withdrawal]
+}
+
+/// Parse a transaction kind by the name used in the `type` column
+fn deserialize_kind<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TransactionKind, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    TransactionKind::from_name(&name).ok_or_else(|| de::Error::custom(format!("unknown transaction kind `{}`", name)))
+}
+
+/// Parse a list of client IDs, which can be written as numbers or strings whichever type client IDs are
+fn deserialize_clients<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<ClientId>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Listed {
+        Number(u64),
+        Text(String),
+    }
+
+    Vec::<Listed>::deserialize(deserializer)?.into_iter()
+        .map(|listed| match listed {
+            Listed::Number(number) => number.to_string(),
+            Listed::Text(text) => text,
+        })
+        .map(|id| id.parse().map_err(|_| de::Error::custom(format!("invalid client ID `{}`", id))))
+        .collect()
+}
+
+/// Parse a list of transaction kinds by the names used in the `type` column
+fn deserialize_kinds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<TransactionKind>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?.iter()
+        .map(|name| TransactionKind::from_name(name)
+            .ok_or_else(|| de::Error::custom(format!("unknown transaction kind `{}`", name))))
+        .collect()
+}
+
+
+impl PolicyConfig {
+    /// Check the limits of the policy can ever be met, describing the first problem found
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            PolicyConfig::Velocity { window_seconds, max_count, max_amount, .. } => {
+                if *window_seconds == 0 { return Err("velocity window_seconds must be at least 1".to_string()); }
+                if max_count.is_none() && max_amount.is_none() {
+                    return Err("velocity needs max_count or max_amount".to_string());
+                }
+                non_negative("max_amount", *max_amount)
+            },
+            PolicyConfig::MaxAmount { max, .. } => non_negative("max", Some(*max)),
+            PolicyConfig::BlockedClients { .. } => Ok(()),
+            PolicyConfig::KindLimit { max_count, max_total, .. } => {
+                if max_count.is_none() && max_total.is_none() {
+                    return Err("kind_limit needs max_count or max_total".to_string());
+                }
+                non_negative("max_total", *max_total)
+            },
+        }
+    }
+
+    /// Create the policy described by the configuration
+    pub fn build(&self) -> Box<dyn Policy> {
+        match self.clone() {
+            PolicyConfig::Velocity { window_seconds, max_count, max_amount, kinds, action } => Box::new(Velocity {
+                window: TimeDelta::seconds(window_seconds as i64), max_count, max_amount, kinds, action,
+                recent: ClientState::new(),
+            }),
+            PolicyConfig::MaxAmount { max, kinds, action } => Box::new(MaxAmount { max, kinds, action }),
+            PolicyConfig::BlockedClients { clients, action } => Box::new(BlockedClients {
+                clients: clients.into_iter().collect(), action,
+            }),
+            PolicyConfig::KindLimit { kind, max_count, max_total, action } => Box::new(KindLimit {
+                kind, max_count, max_total, action, totals: ClientState::new(),
+            }),
+        }
+    }
+}
+
+/// Check a configured amount isn't negative
+fn non_negative(name: &str, amount: Option<Decimal>) -> Result<(), String> {
+    match amount {
+        Some(amount) if amount < Decimal::ZERO => Err(format!("{} can't be negative, got {}", name, amount)),
+        _ => Ok(()),
+    }
+}
+
+/// Create every configured policy in the order they are listed
+pub fn build_policies(configs: &[PolicyConfig]) -> Vec<Box<dyn Policy>> {
+    configs.iter().map(PolicyConfig::build).collect()
+}
+
+
+/// Limits the transactions of each client within a sliding window of time
+pub struct Velocity {
+    window: TimeDelta,
+    max_count: Option<usize>,
+    max_amount: Option<Decimal>,
+    kinds: Vec<TransactionKind>,
+    action: Action,
+    /// Time and amount of the accepted transactions of each client still inside the window
+    recent: ClientState<VecDeque<(DateTime<Utc>, Decimal)>>,
+}
+
+impl Policy for Velocity {
+    fn before(&mut self, transaction: &Transaction, _store: &Store) -> Decision {
+        if !self.kinds.contains(&transaction.kind()) { return Decision::Accept; }
+        let Some(recent) = self.recent.get_mut(transaction.client_id()) else { return Decision::Accept };
+        while recent.front().is_some_and(|(time, _)| transaction.timestamp() - *time > self.window) {
+            recent.pop_front();
+        }
+
+        let amount: Decimal = recent.iter().map(|(_, amount)| *amount).sum::<Decimal>() + transaction.amount();
+        if self.max_count.is_some_and(|max| recent.len() >= max) || self.max_amount.is_some_and(|max| amount > max) {
+            return self.action.decide("velocity_limit");
+        }
+        Decision::Accept
+    }
+
+    fn after(&mut self, transaction: &Transaction, result: Result<(), TransactionError>, _store: &Store) -> Decision {
+        if result.is_ok() && self.kinds.contains(&transaction.kind()) {
+            self.recent.entry(transaction.client_id()).push_back((transaction.timestamp(), transaction.amount()));
+        }
+        Decision::Accept
+    }
+
+    fn savepoint(&mut self) { self.recent.savepoint(); }
+    fn rollback(&mut self) { self.recent.rollback(); }
+    fn release_savepoint(&mut self) { self.recent.release_savepoint(); }
+}
+
+
+/// Limits the amount of a single transaction
+pub struct MaxAmount {
+    max: Decimal,
+    kinds: Vec<TransactionKind>,
+    action: Action,
+}
+
+impl Policy for MaxAmount {
+    fn before(&mut self, transaction: &Transaction, _store: &Store) -> Decision {
+        if self.kinds.contains(&transaction.kind()) && transaction.amount() > self.max {
+            return self.action.decide("max_amount");
+        }
+        Decision::Accept
+    }
+}
+
+
+/// Stops every transaction on the accounts of a set of clients
+pub struct BlockedClients {
+    clients: HashSet<ClientId>,
+    action: Action,
+}
+
+impl Policy for BlockedClients {
+    fn before(&mut self, transaction: &Transaction, _store: &Store) -> Decision {
+        if self.clients.contains(&transaction.client_id()) {
+            return self.action.decide("blocked_client");
+        }
+        Decision::Accept
+    }
+}
+
+
+/// Limits the number and total amount of transactions of one kind for each client
+pub struct KindLimit {
+    kind: TransactionKind,
+    max_count: Option<u64>,
+    max_total: Option<Decimal>,
+    action: Action,
+    /// Number and total amount of the accepted transactions of each client
+    totals: ClientState<(u64, Decimal)>,
+}
+
+impl Policy for KindLimit {
+    fn before(&mut self, transaction: &Transaction, _store: &Store) -> Decision {
+        if transaction.kind() != self.kind { return Decision::Accept; }
+        let (count, total) = self.totals.get(transaction.client_id()).copied().unwrap_or_default();
+        if self.max_count.is_some_and(|max| count >= max)
+            || self.max_total.is_some_and(|max| total + transaction.amount() > max) {
+            return self.action.decide("kind_limit");
+        }
+        Decision::Accept
+    }
+
+    fn after(&mut self, transaction: &Transaction, result: Result<(), TransactionError>, _store: &Store) -> Decision {
+        if result.is_ok() && transaction.kind() == self.kind {
+            let (count, total) = self.totals.entry(transaction.client_id());
+            *count += 1;
+            *total += transaction.amount();
+        }
+        Decision::Accept
+    }
+
+    fn savepoint(&mut self) { self.totals.savepoint(); }
+    fn rollback(&mut self) { self.totals.rollback(); }
+    fn release_savepoint(&mut self) { self.totals.release_savepoint(); }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::id::num_id;
+
+    use super::*;
+
+    fn withdrawal(client: u64, amount: i64, seconds: i64) -> Transaction {
+        let start = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        Transaction::new(TransactionKind::Withdrawal, num_id(client), num_id(1), Decimal::from(amount))
+            .with_timestamp(start + TimeDelta::seconds(seconds))
+    }
+
+    /// Consult the policy around a successful execution of each transaction, collecting the decisions before
+    fn decisions(policy: &mut dyn Policy, transactions: &[Transaction]) -> Vec<Decision> {
+        let store = Store::new();
+        transactions.iter().map(|transaction| {
+            let decision = policy.before(transaction, &store);
+            if decision == Decision::Accept { policy.after(transaction, Ok(()), &store); }
+            decision
+        }).collect()
+    }
+
+    #[test]
+    fn velocity() {
+        let config: PolicyConfig = toml::from_str("type = \"velocity\"\nwindow_seconds = 60\nmax_count = 2\n").unwrap();
+        let decisions = decisions(config.build().as_mut(), &[
+            withdrawal(1, 10, 0), withdrawal(1, 10, 10), withdrawal(1, 10, 20), withdrawal(2, 10, 20),
+            withdrawal(1, 10, 61),
+        ]);
+        assert_eq!(decisions, vec![
+            Decision::Accept, Decision::Accept, Decision::Reject("velocity_limit"), Decision::Accept, Decision::Accept,
+        ]);
+    }
+
+    #[test]
+    fn max_amount_review() {
+        let config: PolicyConfig = toml::from_str("type = \"max_amount\"\nmax = 100\naction = \"review\"\n").unwrap();
+        let decisions = decisions(config.build().as_mut(), &[withdrawal(1, 100, 0), withdrawal(1, 101, 0)]);
+        assert_eq!(decisions, vec![Decision::Accept, Decision::Review("max_amount")]);
+    }
+
+    #[test]
+    fn blocked_clients() {
+        let config: PolicyConfig = toml::from_str("type = \"blocked_clients\"\nclients = [2]\n").unwrap();
+        let decisions = decisions(config.build().as_mut(), &[withdrawal(1, 10, 0), withdrawal(2, 10, 0)]);
+        assert_eq!(decisions, vec![Decision::Accept, Decision::Reject("blocked_client")]);
+    }
+
+    #[test]
+    fn kind_limit() {
+        let config: PolicyConfig = toml::from_str("type = \"kind_limit\"\nkind = \"withdrawal\"\nmax_total = 25\n").unwrap();
+        let decisions = decisions(config.build().as_mut(), &[withdrawal(1, 10, 0), withdrawal(1, 10, 0), withdrawal(1, 10, 0)]);
+        assert_eq!(decisions, vec![Decision::Accept, Decision::Accept, Decision::Reject("kind_limit")]);
+    }
+
+    #[test]
+    fn unknown_kind() {
+        let result = toml::from_str::<PolicyConfig>("type = \"kind_limit\"\nkind = \"refund\"\nmax_count = 1\n");
+        assert!(result.unwrap_err().to_string().contains("unknown transaction kind `refund`"));
+    }
+
+}
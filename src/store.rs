@@ -1,51 +1,489 @@
-use std::collections::{HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{self, BufReader, BufWriter, Write};
 
-use crate::{transaction::{TransactionId, Transaction}, client::{ClientId, Client}};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    transaction::{
+        OpeningBalance, Reversal, ReversalId, TransactionId, Transaction, TransactionError, TransactionRecord,
+        TransactionState,
+    },
+    client::{ClientId, Client, ClientProfile}, dispute::{Dispute, DisputeId}, error::Error, id::Id,
+};
+
+
+/// Limits on how long transactions are kept in storage, once evicted they can no longer be disputed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    /// Maximum number of transactions kept, the oldest are evicted first
+    pub max_count: Option<usize>,
+    /// Number of rows processed after a transaction for which it is still kept
+    pub max_age: Option<u64>,
+    /// Evict transactions as soon as their dispute has been resolved or charged back
+    pub evict_finalized: bool,
+}
+
+impl RetentionPolicy {
+    /// Whether the order transactions were saved in has to be tracked to enforce the policy
+    fn tracks_order(&self) -> bool {
+        self.max_count.is_some() || self.max_age.is_some()
+    }
+}
+
+
+/// Set of IDs stored as inclusive ranges, as transactions are mostly evicted and batches closed in the order of their IDs,
+/// IDs without a successor such as strings are a range each
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(crate) struct IdRanges<I: Id = TransactionId>(BTreeMap<I, I>);
+
+impl<I: Id> Default for IdRanges<I> {
+    fn default() -> IdRanges<I> {
+        IdRanges(BTreeMap::new())
+    }
+}
+
+impl<I: Id> IdRanges<I> {
+    pub(crate) fn contains(&self, id: I) -> bool {
+        self.0.range(..=id).next_back().is_some_and(|(_, end)| *end >= id)
+    }
+
+    pub(crate) fn insert(&mut self, id: I) {
+        if self.contains(id) { return; }
+
+        // Join up with the ranges directly before and after the ID
+        let start = match self.0.range(..id).next_back() {
+            Some((start, end)) if end.successor() == Some(id) => *start,
+            _ => id,
+        };
+        let end = id.successor().and_then(|next| self.0.remove(&next)).unwrap_or(id);
+        self.0.insert(start, end);
+    }
+}
+
+
+/// Changes made since a savepoint, holding what they replaced so they can be undone
+#[derive(Debug, Default, PartialEq)]
+struct Savepoint {
+    clients: HashMap<ClientId, Option<Client>>,
+    transactions: HashMap<TransactionId, Option<TransactionRecord>>,
+    disputes: HashMap<DisputeId, Option<Dispute>>,
+    next_dispute: DisputeId,
+    /// Reversals are only ever added, the ones from this ID on are dropped
+    next_reversal: ReversalId,
+    /// Length of the saved queue, which only grows while evictions are put off
+    saved: usize,
+    /// Transactions finalized since the savepoint, evicted once the changes are kept
+    finalized: Vec<TransactionId>,
+}
 
 
 /// Storage for transactions and client data
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Store {
-    transactions: HashMap<TransactionId, Transaction>,
+    transactions: HashMap<TransactionId, TransactionRecord>,
     clients : HashMap<ClientId, Client>,
+    /// Every dispute opened, kept after it's over for reporting
+    #[serde(default)]
+    disputes: HashMap<DisputeId, Dispute>,
+    /// Balances accounts were migrated with, kept for audit
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    openings: HashMap<ClientId, OpeningBalance>,
+    /// ID given to the next dispute opened without one
+    #[serde(default)]
+    next_dispute: DisputeId,
+    /// Every reversal carried out, kept for audit
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    reversals: HashMap<ReversalId, Reversal>,
+    /// ID given to the next reversal
+    #[serde(default)]
+    next_reversal: ReversalId,
+    /// Number of rows processed, used to measure the age of stored transactions
+    #[serde(default)]
+    clock: u64,
+    /// IDs of stored transactions with the clock at the time they were saved, oldest first
+    #[serde(default)]
+    saved: VecDeque<(u64, TransactionId)>,
+    /// IDs of transactions evicted by the retention policy
+    #[serde(default)]
+    evicted: IdRanges,
+    #[serde(skip)]
+    retention: RetentionPolicy,
+    /// Credit limits given to clients when their account is created
+    #[serde(skip)]
+    credit_limits: HashMap<ClientId, Decimal>,
+    /// Master data given to clients when their account is created
+    #[serde(skip)]
+    profiles: HashMap<ClientId, ClientProfile>,
+    /// Only clients with an account are accepted, accounts being opened from the client master file
+    #[serde(skip)]
+    strict_accounts: bool,
+    /// Changes which can still be rolled back, evictions are put off while there is one
+    #[serde(skip)]
+    savepoint: Option<Savepoint>,
 }
 
 impl Store {
     /// Init storage space for transactions and clients
     pub fn new() -> Store {
-        Store {
-            transactions: HashMap::new(),
-            clients: HashMap::new(),
+        Store::default()
+    }
+
+    /// Init storage which evicts transactions according to the retention policy
+    pub fn with_retention(retention: RetentionPolicy) -> Store {
+        Store { retention, ..Store::default() }
+    }
+
+    /// Change the retention policy, evicting anything the new policy doesn't keep
+    pub fn set_retention(&mut self, retention: RetentionPolicy) {
+        if retention.tracks_order() && !self.retention.tracks_order() {
+            // Order of saving was lost, evict in order of IDs instead
+            let mut ids: Vec<TransactionId> = self.transactions.keys().copied().collect();
+            ids.sort_unstable();
+            self.saved = ids.into_iter().map(|id| (self.clock, id)).collect();
         }
+        if !retention.tracks_order() { self.saved.clear(); }
+        self.retention = retention;
+
+        if retention.evict_finalized {
+            let finalized: Vec<TransactionId> = self.transactions.iter()
+                .filter(|(_, record)| record.state().finalized())
+                .map(|(id, _)| *id)
+                .collect();
+            finalized.into_iter().for_each(|id| self.evict(id));
+        }
+        self.enforce_retention();
+    }
+
+    /// Advance the clock by a processed row, evicting transactions which got too old
+    pub fn tick(&mut self) {
+        self.clock += 1;
+        if self.retention.max_age.is_some() && self.savepoint.is_none() { self.enforce_retention(); }
+    }
+
+    /// Set the credit limits of clients, applied to existing accounts straight away and to the rest once created
+    pub fn set_credit_limits(&mut self, limits: impl IntoIterator<Item = (ClientId, Decimal)>) {
+        for (id, limit) in limits {
+            if let Some(client) = self.clients.get_mut(&id) { client.set_credit_limit(limit); }
+            self.credit_limits.insert(id, limit);
+        }
+    }
+
+    /// Set the master data of clients, applied to existing accounts straight away and to the rest once created, in
+    /// strict mode an account is opened for every client and no other clients are accepted
+    pub fn set_profiles(&mut self, profiles: impl IntoIterator<Item = (ClientId, ClientProfile)>, strict: bool) {
+        for (id, profile) in profiles {
+            if let Some(client) = self.clients.get_mut(&id) { client.set_profile(profile.clone()); }
+            self.profiles.insert(id, profile);
+            if strict { self.get_or_create_client(id); }
+        }
+        self.strict_accounts = strict;
+    }
+
+    /// Whether transactions of the client are accepted, which is any client unless accounts are strict
+    pub fn accepts_client(&self, id: ClientId) -> bool {
+        !self.strict_accounts || self.clients.contains_key(&id)
     }
 
     /// Get client account with ID, if not found, create a new account with the ID and return it
     pub fn get_or_create_client(&mut self, id: ClientId) -> &mut Client {
-        self.clients.entry(id).or_insert(Client::default(id))
+        if let Some(savepoint) = &mut self.savepoint {
+            savepoint.clients.entry(id).or_insert_with(|| self.clients.get(&id).cloned());
+        }
+        let (credit_limits, profiles) = (&self.credit_limits, &self.profiles);
+        self.clients.entry(id).or_insert_with(|| {
+            let mut client = Client::default(id);
+            if let Some(limit) = credit_limits.get(&id) { client.set_credit_limit(*limit); }
+            if let Some(profile) = profiles.get(&id) { client.set_profile(profile.clone()); }
+            client
+        })
+    }
+
+    /// Open an account with the balances it was migrated with, recording them with held funds kept in an open dispute
+    /// over the opening balance (return false if the client already has an account)
+    pub fn open_account(&mut self, mut opening: OpeningBalance) -> bool {
+        if self.clients.contains_key(&opening.client_id()) { return false; }
+        tracing::debug!(client = %opening.client_id(), total = %opening.total(), "account opened");
+        self.get_or_create_client(opening.client_id()).open(opening.available(), opening.held(), opening.locked());
+        if !opening.held().is_zero() {
+            let id = self.next_dispute_id();
+            self.save_dispute(Dispute::opening(id, opening.client_id(), opening.held(), opening.timestamp()));
+            opening = opening.with_dispute(id);
+        }
+        self.openings.insert(opening.client_id(), opening);
+        true
+    }
+
+    /// Get the balances a client's account was opened with, if it was migrated
+    pub fn get_opening(&self, id: ClientId) -> Option<&OpeningBalance> {
+        self.openings.get(&id)
+    }
+
+    /// Get client account with ID without creating it
+    pub fn get_client(&self, id: ClientId) -> Option<&Client> {
+        self.clients.get(&id)
     }
 
     /// Insert transaction for storage
-    pub fn save_transaction(&mut self, transaction: Transaction) {
-        self.transactions.insert(transaction.id(), transaction);
+    pub fn save_transaction(&mut self, transaction: &Transaction) {
+        let _span = tracing::trace_span!("store", tx = %transaction.id()).entered();
+        let record = transaction.record();
+        tracing::trace!(state = record.state().name(), "transaction saved");
+        self.journal_transaction(transaction.id());
+        self.transactions.insert(transaction.id(), record);
+        if self.retention.tracks_order() {
+            self.saved.push_back((self.clock, transaction.id()));
+            if self.savepoint.is_none() { self.enforce_retention(); }
+        }
+    }
+
+    /// Check whether a transaction with the ID is stored or was stored before being evicted
+    pub fn contains_transaction(&self, id: TransactionId) -> bool {
+        self.transactions.contains_key(&id) || self.evicted.contains(id)
     }
 
     /// Get transaction from storage
-    pub fn get_transaction(&mut self, id: TransactionId) -> Option<&mut Transaction> {
-        self.transactions.get_mut(&id)
+    pub fn get_transaction(&self, id: TransactionId) -> Result<&TransactionRecord, TransactionError> {
+        match self.transactions.get(&id) {
+            Some(record) => Ok(record),
+            None if self.evicted.contains(id) => Err(TransactionError::EvictedTransaction),
+            None => Err(TransactionError::UnknownTransaction),
+        }
+    }
+
+    /// Replace a stored transaction with its record after a step of a dispute
+    pub fn update_transaction(&mut self, id: TransactionId, record: TransactionRecord) {
+        let _span = tracing::trace_span!("store", tx = %id).entered();
+        self.journal_transaction(id);
+        if let Some(stored) = self.transactions.get_mut(&id) {
+            tracing::trace!(from = stored.state().name(), to = record.state().name(), "transaction state changed");
+            *stored = record;
+            if record.state().finalized() && self.retention.evict_finalized {
+                match &mut self.savepoint {
+                    Some(savepoint) => savepoint.finalized.push(id),
+                    None => self.evict(id),
+                }
+            }
+        }
+    }
+
+    /// ID to give a dispute opened without one
+    pub fn next_dispute_id(&self) -> DisputeId {
+        self.next_dispute
+    }
+
+    /// Insert or update a dispute
+    pub fn save_dispute(&mut self, dispute: Dispute) {
+        tracing::trace!(dispute = dispute.id(), tx = ?dispute.tx(), status = dispute.status().name(), "dispute saved");
+        self.next_dispute = self.next_dispute.max(dispute.id().saturating_add(1));
+        if let Some(savepoint) = &mut self.savepoint {
+            savepoint.disputes.entry(dispute.id()).or_insert_with(|| self.disputes.get(&dispute.id()).copied());
+        }
+        self.disputes.insert(dispute.id(), dispute);
+    }
+
+    /// Get dispute from storage
+    pub fn get_dispute(&self, id: DisputeId) -> Option<&Dispute> {
+        self.disputes.get(&id)
+    }
+
+    /// Iterate over all stored disputes
+    pub fn disputes(&self) -> impl Iterator<Item = &Dispute> {
+        self.disputes.values()
+    }
+
+    /// ID to give the next reversal
+    pub fn next_reversal_id(&self) -> ReversalId {
+        self.next_reversal
+    }
+
+    /// Insert a reversal
+    pub fn save_reversal(&mut self, reversal: Reversal) {
+        tracing::trace!(reversal = reversal.id(), tx = %reversal.tx(), "reversal saved");
+        self.next_reversal = self.next_reversal.max(reversal.id().saturating_add(1));
+        self.reversals.insert(reversal.id(), reversal);
+    }
+
+    /// Get reversal from storage
+    pub fn get_reversal(&self, id: ReversalId) -> Option<&Reversal> {
+        self.reversals.get(&id)
+    }
+
+    /// Iterate over all stored reversals
+    pub fn reversals(&self) -> impl Iterator<Item = &Reversal> {
+        self.reversals.values()
+    }
+
+    /// Start keeping track of changes so they can be rolled back, replacing the savepoint there already is
+    pub fn savepoint(&mut self) {
+        tracing::trace!("savepoint");
+        self.savepoint = Some(Savepoint {
+            next_dispute: self.next_dispute, next_reversal: self.next_reversal, saved: self.saved.len(),
+            ..Savepoint::default()
+        });
+    }
+
+    /// Keep the changes made since the savepoint, carrying out the evictions put off in the meantime
+    pub fn release_savepoint(&mut self) {
+        let Some(savepoint) = self.savepoint.take() else { return };
+        tracing::trace!("savepoint released");
+        savepoint.finalized.into_iter().for_each(|id| self.evict(id));
+        self.enforce_retention();
+    }
+
+    /// Undo every change made since the savepoint
+    pub fn rollback(&mut self) {
+        let Some(savepoint) = self.savepoint.take() else { return };
+        tracing::debug!(clients = savepoint.clients.len(), transactions = savepoint.transactions.len(), "rolled back");
+        restore(&mut self.clients, savepoint.clients);
+        restore(&mut self.transactions, savepoint.transactions);
+        restore(&mut self.disputes, savepoint.disputes);
+        self.next_dispute = savepoint.next_dispute;
+        self.reversals.retain(|id, _| *id < savepoint.next_reversal);
+        self.next_reversal = savepoint.next_reversal;
+        self.saved.truncate(savepoint.saved);
+    }
+
+    /// Whether there is a savepoint changes can be rolled back to
+    pub fn in_savepoint(&self) -> bool {
+        self.savepoint.is_some()
+    }
+
+    /// Note what a stored transaction was before it changes, if there is a savepoint
+    fn journal_transaction(&mut self, id: TransactionId) {
+        if let Some(savepoint) = &mut self.savepoint {
+            savepoint.transactions.entry(id).or_insert_with(|| self.transactions.get(&id).copied());
+        }
+    }
+
+    /// Remove a transaction from storage, remembering it was there
+    fn evict(&mut self, id: TransactionId) {
+        if self.transactions.remove(&id).is_some() {
+            tracing::debug!(tx = %id, "transaction evicted");
+            self.evicted.insert(id);
+        }
+    }
+
+    /// Evict the oldest transactions until the store is within the limits of the retention policy
+    fn enforce_retention(&mut self) {
+        let RetentionPolicy { max_count, max_age, .. } = self.retention;
+
+        // Every transaction is looked at once at most, so held up disputes can't keep this going forever
+        for _ in 0..self.saved.len() {
+            let Some(&(saved_at, id)) = self.saved.front() else { break };
+            let over_count = max_count.is_some_and(|max| self.transactions.len() > max);
+            let over_age = max_age.is_some_and(|max| self.clock - saved_at > max);
+            if !over_count && !over_age { break; }
+
+            self.saved.pop_front();
+            match self.transactions.get(&id) {
+                // Disputed funds are held until resolved or charged back, so the transaction has to stay around
+                Some(record) if record.state() == TransactionState::Disputed => self.saved.push_back((self.clock, id)),
+                Some(_) => self.evict(id),
+                // Evicted earlier as its dispute was finalized
+                None => (),
+            }
+        }
+    }
+
+    /// Iterate over all stored client accounts
+    pub fn clients(&self) -> impl Iterator<Item = &Client> {
+        self.clients.values()
+    }
+
+    /// Whether any client has master data, which adds its columns to the CSV outputs
+    pub fn has_profiles(&self) -> bool {
+        self.clients.values().any(|client| client.profile().is_some())
+    }
+
+    /// Number of transactions kept in storage
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Iterate over all stored transactions with their IDs
+    pub fn transactions(&self) -> impl Iterator<Item = (TransactionId, &TransactionRecord)> {
+        self.transactions.iter().map(|(id, record)| (*id, record))
+    }
+
+    /// Persist the whole store as a JSON snapshot so processing can be picked up again later
+    pub fn save_snapshot(&self, path: &str) -> Result<(), Error> {
+        let file = File::create(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+        let mut out = BufWriter::new(file);
+        serde_json::to_writer(&mut out, self)
+            .map_err(|err| Error::Snapshot { path: path.to_string(), message: err.to_string() })?;
+        out.flush().map_err(|source| Error::Io { path: path.to_string(), source })?;
+        tracing::info!(path, clients = self.clients.len(), transactions = self.transactions.len(), "snapshot saved");
+        Ok(())
+    }
+
+    /// Restore a store from a snapshot written by `save_snapshot`
+    pub fn load_snapshot(path: &str) -> Result<Store, Error> {
+        let file = File::open(path).map_err(|source| Error::Io { path: path.to_string(), source })?;
+        let store: Store = serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| Error::Snapshot { path: path.to_string(), message: err.to_string() })?;
+        tracing::info!(path, clients = store.clients.len(), transactions = store.transactions.len(), "snapshot loaded");
+        Ok(store)
     }
 
     /// Output a CSV of all customer records
     pub fn print_clients(&self) {
+        self.write_clients(&mut io::stdout().lock())
+            .unwrap_or_else(|err| panic!("Couldn't write client records: {}", err));
+    }
+
+    /// Write a CSV of all customer records into the provided writer
+    pub fn write_clients<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+
+        // Master data columns are only written once a client master file was loaded
+        let profiles = self.has_profiles();
 
-        // Print header
-        println!("{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <10}", 
-                "client", "available", "held", "total", "locked");
+        // Write header
+        write!(out, "{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <10}", 
+                "client", "available", "held", "total", "locked")?;
+        if profiles { write!(out, ", {0: <20}, {1: <10}, {2: <10}", "name", "status", "kyc_tier")?; }
+        writeln!(out)?;
 
-        // Print values
-        for (_, client) in &self.clients {
-            println!("{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <10}", 
-                    client.id(), client.available(), client.held(), client.total(), client.locked()); 
+        // Write values
+        for client in self.clients.values() {
+            write!(out, "{0: <10}, {1: <10}, {2: <10}, {3: <10}, {4: <10}", 
+                    client.id(), client.available(), client.held(), client.total(), client.locked())?; 
+            match client.profile() {
+                Some(profile) if profiles => {
+                    write_text_column(out, &profile.name, 20)?;
+                    write!(out, ", {0: <10}, {1: <10}", profile.status.name(), profile.kyc_tier)?;
+                },
+                None if profiles => write!(out, ", {0: <20}, {1: <10}, {2: <10}", "", "", "")?,
+                _ => {},
+            }
+            writeln!(out)?;
         }
+        Ok(())
+    }
+}
+
+
+/// Write a free text column of a padded CSV table, quoted if it holds a comma or a quote
+pub(crate) fn write_text_column<W: Write + ?Sized>(out: &mut W, text: &str, width: usize) -> io::Result<()> {
+    if text.contains([',', '"']) {
+        // A quote only opens a field straight after the comma, so the padding space goes after the field instead
+        write!(out, ",{0: <1$}", format!("\"{}\"", text.replace('"', "\"\"")), width + 1)
+    } else {
+        write!(out, ", {0: <1$}", text, width)
+    }
+}
+
+/// Put back the values entries had at a savepoint, removing the ones which didn't exist then
+fn restore<K: Hash + Eq, V>(map: &mut HashMap<K, V>, previous: HashMap<K, Option<V>>) {
+    for (key, value) in previous {
+        match value {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
     }
 }
 
@@ -59,13 +497,32 @@ mod tests {
 
     use rust_decimal::Decimal;
 
+    use chrono::Utc;
+
+    use crate::{client::ClientStatus, dispute::DisputeStatus, transaction::TransactionKind};
+
+    use crate::id::num_id;
+
     use super::*;
 
     #[test]
     fn create_new_store() {
         let test_store = Store {
             transactions: HashMap::new(),
-            clients: HashMap::new()
+            clients: HashMap::new(),
+            disputes: HashMap::new(),
+            openings: HashMap::new(),
+            next_dispute: 0,
+            reversals: HashMap::new(),
+            next_reversal: 0,
+            clock: 0,
+            saved: VecDeque::new(),
+            evicted: IdRanges::default(),
+            retention: RetentionPolicy::default(),
+            credit_limits: HashMap::new(),
+            profiles: HashMap::new(),
+            strict_accounts: false,
+            savepoint: None,
         };
         assert_eq!(test_store, Store::new());
     }
@@ -73,19 +530,198 @@ mod tests {
     #[test]
     fn get_or_create_client() {
         let mut test_store = Store::new();
-        let new_client1 = test_store.get_or_create_client(1);
-        assert_eq!(new_client1.id(), 1);
+        let new_client1 = test_store.get_or_create_client(num_id(1));
+        assert_eq!(new_client1.id(), num_id::<ClientId>(1));
     }
 
     #[test]
     fn get_or_create_client_2() {
         let mut test_store = Store::new();
         test_store.clients.insert(
-            5, Client::new(5, Decimal::from_str("123.4567").unwrap(), Decimal::from_str("123.4567").unwrap(), false)
+            num_id(5),
+            Client::new(num_id(5), Decimal::from_str("123.4567").unwrap(), Decimal::from_str("123.4567").unwrap(), false),
         );
-        let existing_client = test_store.get_or_create_client(5);
-        assert_eq!(existing_client.id(), 5);
+        let existing_client = test_store.get_or_create_client(num_id(5));
+        assert_eq!(existing_client.id(), num_id::<ClientId>(5));
         assert_eq!(existing_client.available(), Decimal::from_str("123.4567").unwrap());
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn snapshot_round_trip() {
+        let mut test_store = Store::new();
+        test_store.get_or_create_client(num_id(2)).deposit(Decimal::from_str("10.25").unwrap());
+        test_store.save_transaction(
+            &Transaction::new(TransactionKind::Deposit, num_id(2), num_id(7), Decimal::from_str("10.25").unwrap()),
+        );
+
+        let path = std::env::temp_dir().join(format!("transactions-store-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        test_store.save_snapshot(path).unwrap();
+        let restored = Store::load_snapshot(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(restored, test_store);
+    }
+
+    /// Store with `count` deposits of 10 each for client 1
+    fn store_with_deposits(retention: RetentionPolicy, count: u64) -> Store {
+        let mut test_store = Store::with_retention(retention);
+        for id in 1..=count {
+            Transaction::new(TransactionKind::Deposit, num_id(1), num_id(id), Decimal::from(10)).exec(&mut test_store).unwrap();
+        }
+        test_store
+    }
+
+    #[test]
+    fn retention_max_count() {
+        let test_store = store_with_deposits(RetentionPolicy { max_count: Some(2), ..RetentionPolicy::default() }, 5);
+        assert_eq!(test_store.transactions().count(), 2);
+        assert_eq!(test_store.get_transaction(num_id(1)), Err(TransactionError::EvictedTransaction));
+        assert_eq!(test_store.get_transaction(num_id(6)), Err(TransactionError::UnknownTransaction));
+        assert!(test_store.get_transaction(num_id(5)).is_ok());
+        assert!(test_store.contains_transaction(num_id(1)));
+    }
+
+    #[test]
+    fn retention_max_age() {
+        let mut test_store = store_with_deposits(RetentionPolicy { max_age: Some(3), ..RetentionPolicy::default() }, 5);
+        assert_eq!(test_store.transactions().count(), 4);
+        test_store.tick();
+        assert_eq!(test_store.get_transaction(num_id(2)), Err(TransactionError::EvictedTransaction));
+        assert!(test_store.get_transaction(num_id(3)).is_ok());
+    }
+
+    #[test]
+    fn retention_keeps_disputed() {
+        let mut test_store = store_with_deposits(RetentionPolicy { max_count: Some(2), ..RetentionPolicy::default() }, 1);
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO).exec(&mut test_store).unwrap();
+        for id in 2..=4 {
+            Transaction::new(TransactionKind::Deposit, num_id(1), num_id(id), Decimal::from(10)).exec(&mut test_store).unwrap();
+        }
+        assert!(test_store.get_transaction(num_id(1)).is_ok());
+        assert_eq!(test_store.get_transaction(num_id(2)), Err(TransactionError::EvictedTransaction));
+    }
+
+    #[test]
+    fn retention_evict_finalized() {
+        let mut test_store = store_with_deposits(RetentionPolicy { evict_finalized: true, ..RetentionPolicy::default() }, 2);
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO).exec(&mut test_store).unwrap();
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(1), Decimal::ZERO).exec(&mut test_store).unwrap();
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO);
+        assert_eq!(dispute.exec(&mut test_store), Err(TransactionError::EvictedTransaction));
+        assert!(test_store.get_transaction(num_id(2)).is_ok());
+    }
+
+    #[test]
+    fn credit_limits() {
+        let mut test_store = Store::new();
+        test_store.get_or_create_client(num_id(1));
+        test_store.set_credit_limits([(num_id(1), Decimal::from(100)), (num_id(2), Decimal::from(50))]);
+        assert_eq!(test_store.get_client(num_id(1)).unwrap().credit_limit(), Decimal::from(100));
+        assert_eq!(test_store.get_or_create_client(num_id(2)).credit_limit(), Decimal::from(50));
+        assert_eq!(test_store.get_or_create_client(num_id(3)).credit_limit(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn opening_balances() {
+        let opened_at = Utc::now();
+        let mut test_store = Store::new();
+        test_store.set_credit_limits([(num_id(1), Decimal::from(20))]);
+        let opening = OpeningBalance::new(num_id(1), Decimal::from(80), Decimal::ZERO, true, opened_at);
+        assert!(test_store.open_account(opening));
+
+        let client = test_store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held(), client.locked()), (Decimal::from(80), Decimal::ZERO, true));
+        assert_eq!(client.credit_limit(), Decimal::from(20));
+        assert_eq!(test_store.get_opening(num_id(1)), Some(&opening));
+
+        test_store.get_or_create_client(num_id(2));
+        assert!(!test_store.open_account(OpeningBalance::new(num_id(2), Decimal::ONE, Decimal::ZERO, false, opened_at)));
+        assert!(!test_store.open_account(opening));
+        assert!(test_store.get_opening(num_id(2)).is_none());
+
+        // Held funds are kept in an open dispute over the opening balance
+        assert!(test_store.open_account(OpeningBalance::new(num_id(3), Decimal::ONE, Decimal::TWO, false, opened_at)));
+        assert_eq!(test_store.get_client(num_id(3)).unwrap().held(), Decimal::TWO);
+        let held = test_store.get_opening(num_id(3)).unwrap().dispute().and_then(|id| test_store.get_dispute(id)).unwrap();
+        assert_eq!((held.tx(), held.client_id(), held.amount()), (None, num_id(3), Decimal::TWO));
+        assert_eq!(held.status(), DisputeStatus::Open);
+
+        let restored: Store = serde_json::from_str(&serde_json::to_string(&test_store).unwrap()).unwrap();
+        assert_eq!(restored.get_opening(num_id(1)), Some(&opening));
+        assert_eq!(restored.get_dispute(held.id()), Some(held));
+    }
+
+    #[test]
+    fn client_master() {
+        let profile = |name: &str| ClientProfile { name: name.to_string(), status: ClientStatus::Active, kyc_tier: 1 };
+        let mut test_store = Store::new();
+        test_store.get_or_create_client(num_id(1));
+        test_store.set_profiles([(num_id(1), profile("Ada")), (num_id(2), profile("Grace"))], false);
+        assert_eq!(test_store.get_client(num_id(1)).unwrap().profile(), Some(&profile("Ada")));
+        assert!(test_store.get_client(num_id(2)).is_none());
+        assert_eq!(test_store.get_or_create_client(num_id(2)).profile(), Some(&profile("Grace")));
+        assert!(test_store.accepts_client(num_id(3)));
+
+        let mut strict_store = Store::new();
+        strict_store.set_profiles([(num_id(1), profile("Ada")), (num_id(2), profile("Smith, John"))], true);
+        assert!(strict_store.get_client(num_id(2)).is_some());
+        assert!(strict_store.accepts_client(num_id(1)));
+        assert!(!strict_store.accepts_client(num_id(3)));
+
+        let mut out = Vec::new();
+        strict_store.write_clients(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.lines().next().unwrap().contains("name"));
+        assert!(text.contains("\"Smith, John\""));
+    }
+
+    #[test]
+    fn rollback() {
+        let mut test_store = store_with_deposits(RetentionPolicy::default(), 2);
+        test_store.savepoint();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO).exec(&mut test_store).unwrap();
+        Transaction::new(TransactionKind::Deposit, num_id(2), num_id(3), Decimal::from(5)).exec(&mut test_store).unwrap();
+        Transaction::new(TransactionKind::Reversal, num_id(1), num_id(2), Decimal::ZERO).exec(&mut test_store).unwrap();
+        test_store.rollback();
+
+        assert!(!test_store.in_savepoint());
+        let client = test_store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(20), Decimal::ZERO));
+        assert!(test_store.get_client(num_id(2)).is_none());
+        assert_eq!(test_store.get_transaction(num_id(1)).unwrap().state(), TransactionState::Processed);
+        assert_eq!(test_store.get_transaction(num_id(3)), Err(TransactionError::UnknownTransaction));
+        assert_eq!((test_store.disputes().count(), test_store.next_dispute_id()), (0, 0));
+        assert_eq!(test_store.get_transaction(num_id(2)).unwrap().reversal(), None);
+        assert_eq!((test_store.reversals().count(), test_store.next_reversal_id()), (0, 0));
+    }
+
+    #[test]
+    fn release_savepoint() {
+        let retention = RetentionPolicy { max_count: Some(2), evict_finalized: true, ..RetentionPolicy::default() };
+        let mut test_store = store_with_deposits(retention, 2);
+        test_store.savepoint();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO).exec(&mut test_store).unwrap();
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(1), Decimal::ZERO).exec(&mut test_store).unwrap();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(3), Decimal::from(10)).exec(&mut test_store).unwrap();
+        // Evictions wait for the changes to be kept
+        assert_eq!(test_store.transaction_count(), 3);
+
+        test_store.release_savepoint();
+        assert_eq!(test_store.get_transaction(num_id(1)), Err(TransactionError::EvictedTransaction));
+        assert_eq!(test_store.transaction_count(), 2);
+        assert_eq!(test_store.get_client(num_id(1)).unwrap().available(), Decimal::from(30));
+    }
+
+    #[test]
+    fn evicted_id_ranges() {
+        let mut ranges = IdRanges::<u32>::default();
+        for id in [3, 1, 2, 7, 5] { ranges.insert(id); }
+        assert_eq!(ranges.0, BTreeMap::from([(1, 3), (5, 5), (7, 7)]));
+        ranges.insert(6);
+        assert_eq!(ranges.0, BTreeMap::from([(1, 3), (5, 7)]));
+        assert!(ranges.contains(6));
+        assert!(!ranges.contains(4));
+    }
+
+}
@@ -1,10 +1,16 @@
+use std::fmt;
+use std::str::FromStr;
 
+use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer, Serialize};
 
-use crate::{client::ClientId, store::Store};
+use crate::{
+    client::ClientId, config::{Rules, SpentDisputes, WithdrawalDisputes},
+    dispute::{Dispute, DisputeId, DisputeStatus, ReasonCode}, store::Store,
+};
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 #[serde(tag="type")]
 #[serde(rename_all="lowercase")]
 pub enum TransactionKind {
@@ -13,12 +19,327 @@ pub enum TransactionKind {
     Dispute,
     Resolve,
     Chargeback,
+    /// Admin transaction setting the client's credit limit to the amount
+    #[serde(rename="credit_limit")]
+    CreditLimit,
+    /// Merchant's successful challenge of a chargeback, giving the charged back funds back
+    Representment,
+    /// Card network's reversal of a chargeback, giving the charged back funds back
+    #[serde(rename="chargeback_reversal")]
+    ChargebackReversal,
+    /// Correction exactly undoing an erroneous deposit or withdrawal
+    Reversal,
 }
 
+impl TransactionKind {
+    /// Name of the kind as used in the `type` column
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionKind::Deposit => "deposit",
+            TransactionKind::Withdrawal => "withdrawal",
+            TransactionKind::Dispute => "dispute",
+            TransactionKind::Resolve => "resolve",
+            TransactionKind::Chargeback => "chargeback",
+            TransactionKind::CreditLimit => "credit_limit",
+            TransactionKind::Representment => "representment",
+            TransactionKind::ChargebackReversal => "chargeback_reversal",
+            TransactionKind::Reversal => "reversal",
+        }
+    }
+
+    /// Kind with the given name as used in the `type` column
+    pub fn from_name(name: &str) -> Option<TransactionKind> {
+        match name {
+            "deposit" => Some(TransactionKind::Deposit),
+            "withdrawal" => Some(TransactionKind::Withdrawal),
+            "dispute" => Some(TransactionKind::Dispute),
+            "resolve" => Some(TransactionKind::Resolve),
+            "chargeback" => Some(TransactionKind::Chargeback),
+            "credit_limit" => Some(TransactionKind::CreditLimit),
+            "representment" => Some(TransactionKind::Representment),
+            "chargeback_reversal" => Some(TransactionKind::ChargebackReversal),
+            "reversal" => Some(TransactionKind::Reversal),
+            _ => None,
+        }
+    }
+}
+
+/// ID of a transaction, widened by the `u64-ids` and `string-ids` features
+#[cfg(not(any(feature = "u64-ids", feature = "string-ids")))]
 pub type TransactionId = u32;
+#[cfg(all(feature = "u64-ids", not(feature = "string-ids")))]
+pub type TransactionId = u64;
+#[cfg(feature = "string-ids")]
+pub type TransactionId = crate::id::StrId;
+/// ID of a batch of rows, a `u32` under every ID feature as batches are only told apart from the rows next to them
+pub type BatchId = u32;
+/// ID of a reversal, a `u32` under every ID feature as reversals are numbered in order by the store
+pub type ReversalId = u32;
+
+/// Reasons for a transaction to be ignored instead of being applied to the account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TransactionError {
+    /// The client's account is locked after a chargeback
+    AccountLocked,
+    /// The client has no account while accounts are only opened from the client master file
+    UnknownClient,
+    /// A deposit or withdrawal reused the ID of a stored transaction
+    DuplicateTransaction,
+    /// A dispute, resolve or chargeback referenced a transaction which isn't stored
+    UnknownTransaction,
+    /// There isn't enough available funds to take the amount from
+    InsufficientFunds,
+    /// There isn't enough funds held in dispute to release the amount from
+    InsufficientHeldFunds,
+    /// The referenced transaction was evicted from storage and can no longer be disputed
+    EvictedTransaction,
+    /// A dispute or reversal referenced a transaction which is in dispute or was charged back
+    AlreadyDisputed,
+    /// A dispute or reversal referenced a transaction which was reversed
+    AlreadyReversed,
+    /// A reversal referenced a failed transaction, which has nothing to undo
+    NotReversible,
+    /// A dispute referenced a failed transaction or a withdrawal while withdrawals can't be disputed
+    NotDisputable,
+    /// A resolve or chargeback referenced a transaction which isn't in dispute
+    NotDisputed,
+    /// A representment or chargeback reversal referenced a transaction or dispute which wasn't charged back
+    NotChargedBack,
+    /// A resolve, chargeback or reversal referenced a dispute which isn't stored or is over another transaction
+    UnknownDispute,
+    /// A dispute reused the ID of a stored dispute
+    DuplicateDispute,
+    /// A dispute arrived after the dispute window of the referenced transaction closed
+    DisputeExpired,
+    /// A buffered reference gave up waiting for the transaction it references to arrive
+    Orphaned,
+    /// The amount can't be used for the transaction, such as a negative credit limit
+    InvalidAmount,
+    /// A policy rejected the transaction for the given reason before it was executed
+    Rejected(&'static str),
+    /// Another transaction of the batch failed, so none of the batch was applied
+    BatchFailed,
+    /// A row of a batch arrived after the batch was applied or rolled back, as its rows weren't consecutive
+    BatchClosed,
+}
+
+impl TransactionError {
+    /// Short machine readable name of the failure, used when reporting
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TransactionError::AccountLocked => "account_locked",
+            TransactionError::UnknownClient => "unknown_client",
+            TransactionError::DuplicateTransaction => "duplicate_transaction",
+            TransactionError::UnknownTransaction => "unknown_transaction",
+            TransactionError::InsufficientFunds => "insufficient_funds",
+            TransactionError::InsufficientHeldFunds => "insufficient_held_funds",
+            TransactionError::EvictedTransaction => "evicted_transaction",
+            TransactionError::AlreadyDisputed => "already_disputed",
+            TransactionError::AlreadyReversed => "already_reversed",
+            TransactionError::NotReversible => "not_reversible",
+            TransactionError::NotDisputable => "not_disputable",
+            TransactionError::NotDisputed => "not_disputed",
+            TransactionError::NotChargedBack => "not_charged_back",
+            TransactionError::UnknownDispute => "unknown_dispute",
+            TransactionError::DuplicateDispute => "duplicate_dispute",
+            TransactionError::DisputeExpired => "dispute_expired",
+            TransactionError::Orphaned => "orphaned",
+            TransactionError::InvalidAmount => "invalid_amount",
+            TransactionError::Rejected(reason) => reason,
+            TransactionError::BatchFailed => "batch_failed",
+            TransactionError::BatchClosed => "batch_closed",
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// Progress of a stored transaction through disputes
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all="snake_case")]
+pub enum TransactionState {
+    /// Applied to the account and not in dispute
+    Processed,
+    /// Couldn't be applied to the account
+    Failed,
+    /// Funds are held until the dispute is resolved or charged back
+    Disputed,
+    /// Dispute ended with the held funds released back to the client
+    Resolved,
+    /// Dispute ended with the held funds removed from the account
+    ChargedBack,
+    /// Undone by a reversal
+    Reversed,
+}
+
+impl TransactionState {
+    /// Name of the state as used in reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionState::Processed => "processed",
+            TransactionState::Failed => "failed",
+            TransactionState::Disputed => "disputed",
+            TransactionState::Resolved => "resolved",
+            TransactionState::ChargedBack => "charged_back",
+            TransactionState::Reversed => "reversed",
+        }
+    }
+
+    /// Whether a new dispute can be opened on a transaction in this state, as long as some of it wasn't charged back
+    pub fn disputable(&self) -> bool {
+        !matches!(self, TransactionState::Failed | TransactionState::Disputed | TransactionState::Reversed)
+    }
+
+    /// Whether the transaction went through a dispute which has since ended, or was reversed
+    pub fn finalized(&self) -> bool {
+        matches!(self, TransactionState::Resolved | TransactionState::ChargedBack | TransactionState::Reversed)
+    }
+}
+
+/// Compact record of a deposit or withdrawal, all that storage needs to keep for it to be disputed later
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct TransactionRecord {
+    #[serde(flatten)]
+    kind: TransactionKind,
+    #[serde(rename="client")]
+    client_id: ClientId,
+    amount: Decimal,
+    state: TransactionState,
+    #[serde(default = "Utc::now")]
+    timestamp: DateTime<Utc>,
+    /// Latest dispute over the transaction, open while the transaction is disputed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dispute: Option<DisputeId>,
+    /// Part of the amount taken back by earlier chargebacks, which can't be disputed again
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    charged_back: Decimal,
+    /// Reversal undoing the transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reversal: Option<ReversalId>,
+}
+
+impl TransactionRecord {
+    // GETTERS
+    pub fn kind(&self) -> TransactionKind { self.kind }
+    pub fn client_id(&self) -> ClientId { self.client_id }
+    pub fn amount(&self) -> Decimal { self.amount }
+    pub fn state(&self) -> TransactionState { self.state }
+    pub fn timestamp(&self) -> DateTime<Utc> { self.timestamp }
+    pub fn dispute(&self) -> Option<DisputeId> { self.dispute }
+    pub fn charged_back(&self) -> Decimal { self.charged_back }
+    pub fn reversal(&self) -> Option<ReversalId> { self.reversal }
+
+    /// Part of the amount which can still be disputed
+    pub fn disputable_amount(&self) -> Decimal { self.amount - self.charged_back }
+
+    /// Put part of the amount in the given dispute
+    pub fn open_dispute(&mut self, dispute: DisputeId) {
+        self.state = TransactionState::Disputed;
+        self.dispute = Some(dispute);
+    }
+
+    /// End the dispute with the disputed part left with the client
+    pub fn resolve(&mut self) {
+        self.state = TransactionState::Resolved;
+    }
+
+    /// End the dispute with the disputed amount taken back, the rest stays disputable
+    pub fn charge_back(&mut self, amount: Decimal) {
+        self.state = TransactionState::ChargedBack;
+        self.charged_back += amount;
+    }
+
+    /// Mark the transaction as undone by the given reversal
+    pub fn reverse(&mut self, reversal: ReversalId) {
+        self.state = TransactionState::Reversed;
+        self.reversal = Some(reversal);
+    }
+
+    /// Give back an amount taken by a chargeback which was overturned, leaving it with the client as if resolved
+    pub fn reverse_chargeback(&mut self, amount: Decimal) {
+        self.state = TransactionState::Resolved;
+        self.charged_back -= amount;
+    }
+}
+
+/// Synthetic transaction recording the balances an account was migrated with, kept for audit
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(tag="type", rename="opening_balance")]
+pub struct OpeningBalance {
+    #[serde(rename="client")]
+    client_id: ClientId,
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+    timestamp: DateTime<Utc>,
+    /// Dispute holding the held funds until a resolve or chargeback naming it releases them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dispute: Option<DisputeId>,
+}
+
+impl OpeningBalance {
+    // GETTERS
+    pub fn client_id(&self) -> ClientId { self.client_id }
+    pub fn available(&self) -> Decimal { self.available }
+    pub fn held(&self) -> Decimal { self.held }
+    pub fn locked(&self) -> bool { self.locked }
+    pub fn timestamp(&self) -> DateTime<Utc> { self.timestamp }
+    pub fn dispute(&self) -> Option<DisputeId> { self.dispute }
+
+    /// Record the balances a client's account is opened with
+    pub fn new(
+        client_id: ClientId, available: Decimal, held: Decimal, locked: bool, timestamp: DateTime<Utc>,
+    ) -> OpeningBalance {
+        OpeningBalance { client_id, available, held, locked, timestamp, dispute: None }
+    }
+
+    /// Set the dispute the held funds are kept in
+    pub fn with_dispute(mut self, dispute: DisputeId) -> OpeningBalance {
+        self.dispute = Some(dispute);
+        self
+    }
+
+    /// Balance of the account, available and held funds together
+    pub fn total(&self) -> Decimal { self.available + self.held }
+}
+
+/// Reversal which undid a deposit or withdrawal, kept for audit with a link from the transaction it reversed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(tag="type", rename="reversal")]
+pub struct Reversal {
+    id: ReversalId,
+    /// Transaction the reversal undid
+    tx: TransactionId,
+    #[serde(rename="client")]
+    client_id: ClientId,
+    amount: Decimal,
+    timestamp: DateTime<Utc>,
+}
+
+impl Reversal {
+    // GETTERS
+    pub fn id(&self) -> ReversalId { self.id }
+    pub fn tx(&self) -> TransactionId { self.tx }
+    pub fn client_id(&self) -> ClientId { self.client_id }
+    pub fn amount(&self) -> Decimal { self.amount }
+    pub fn timestamp(&self) -> DateTime<Utc> { self.timestamp }
+
+    /// Record the reversal of an amount of a transaction
+    pub fn new(
+        id: ReversalId, tx: TransactionId, client_id: ClientId, amount: Decimal, timestamp: DateTime<Utc>,
+    ) -> Reversal {
+        Reversal { id, tx, client_id, amount, timestamp }
+    }
+}
 
 /// Transaction to be carried out on an account
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub struct Transaction {
     #[serde(alias="type")]
     #[serde(flatten)]
@@ -27,98 +348,450 @@ pub struct Transaction {
     client_id: ClientId,
     #[serde(rename="tx")]
     transaction_id: TransactionId,
+    /// Amount moved, or for disputes the part of the referenced transaction disputed with zero meaning all of it
+    #[serde(default, deserialize_with = "deserialize_amount")]
     amount: Decimal,
     #[serde(skip_deserializing)]
     success: bool,
+    /// Time the transaction happened, stamped with the time it was read if the input doesn't provide it
+    #[serde(default = "Utc::now", deserialize_with = "deserialize_timestamp")]
+    timestamp: DateTime<Utc>,
+    /// Dispute opened by a dispute row, given one when it's left out, or ended by a resolve or chargeback row, the
+    /// open dispute of the referenced transaction when it's left out
+    #[serde(default, rename="dispute", deserialize_with = "deserialize_optional_id", skip_serializing_if = "Option::is_none")]
+    dispute_id: Option<DisputeId>,
+    /// Reason given by a dispute or chargeback row, which a chargeback sets on the dispute it ends
+    #[serde(default, deserialize_with = "deserialize_reason", skip_serializing_if = "Option::is_none")]
+    reason: Option<ReasonCode>,
+    /// Batch of consecutive rows which are applied together or not at all
+    #[serde(default, deserialize_with = "deserialize_optional_id", skip_serializing_if = "Option::is_none")]
+    batch: Option<BatchId>,
+}
+
+
+/// Parse the amount column, which is optional for the rows referencing another transaction so an empty or missing
+/// amount is zero
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+    struct AmountVisitor;
+
+    impl<'de> de::Visitor<'de> for AmountVisitor {
+        type Value = Decimal;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a decimal amount")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            let value = value.trim();
+            if value.is_empty() { return Ok(Decimal::ZERO); }
+            Decimal::from_str(value).or_else(|_| Decimal::from_scientific(value)).map_err(E::custom)
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> { Ok(Decimal::from(value)) }
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> { Ok(Decimal::from(value)) }
+
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+            // The shortest representation reading back as the same float is the one that was written
+            self.visit_str(&value.to_string())
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> { Ok(Decimal::ZERO) }
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> { Ok(Decimal::ZERO) }
+
+        fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+    }
+
+    deserializer.deserialize_any(AmountVisitor)
+}
+
+/// Parse the optional dispute and batch columns, which are left empty on most rows, and are numeric whichever type
+/// client and transaction IDs are
+fn deserialize_optional_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    struct IdVisitor;
+
+    impl<'de> de::Visitor<'de> for IdVisitor {
+        type Value = Option<u32>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an ID")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            let value = value.trim();
+            if value.is_empty() { return Ok(None); }
+            value.parse().map(Some).map_err(E::custom)
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+            u32::try_from(value).map(Some).map_err(E::custom)
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+            u32::try_from(value).map(Some).map_err(E::custom)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> { Ok(None) }
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> { Ok(None) }
+
+        fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+    }
+
+    deserializer.deserialize_any(IdVisitor)
+}
+
+/// Parse the optional reason column, which is left empty on most rows
+fn deserialize_reason<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ReasonCode>, D::Error> {
+    let Some(name) = Option::<String>::deserialize(deserializer)? else { return Ok(None) };
+    let name = name.trim();
+    if name.is_empty() { return Ok(None); }
+    ReasonCode::from_name(name).map(Some).ok_or_else(|| de::Error::custom(format!("unknown reason code `{}`", name)))
+}
+
+/// Parse the optional timestamp column as RFC 3339 or seconds since the Unix epoch, stamping the current time if it's
+/// empty
+fn deserialize_timestamp<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    struct TimestampVisitor;
+
+    impl<'de> de::Visitor<'de> for TimestampVisitor {
+        type Value = Option<DateTime<Utc>>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an RFC 3339 timestamp or seconds since the Unix epoch")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            let value = value.trim();
+            if value.is_empty() { return Ok(None); }
+            if let Ok(seconds) = value.parse::<i64>() { return self.visit_i64(seconds); }
+            DateTime::parse_from_rfc3339(value).map(|time| Some(time.with_timezone(&Utc))).map_err(E::custom)
+        }
+
+        fn visit_i64<E: de::Error>(self, seconds: i64) -> Result<Self::Value, E> {
+            DateTime::from_timestamp(seconds, 0).map(Some).ok_or_else(|| E::custom("timestamp out of range"))
+        }
+
+        fn visit_u64<E: de::Error>(self, seconds: u64) -> Result<Self::Value, E> {
+            self.visit_i64(i64::try_from(seconds).map_err(E::custom)?)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> { Ok(None) }
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> { Ok(None) }
+
+        fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+    }
+
+    Ok(deserializer.deserialize_any(TimestampVisitor)?.unwrap_or_else(Utc::now))
 }
 
 
 impl Transaction {
     // GETTERS
     pub fn id(&self) -> TransactionId { self.transaction_id }
+    pub fn kind(&self) -> TransactionKind { self.kind }
+    pub fn client_id(&self) -> ClientId { self.client_id }
+    pub fn amount(&self) -> Decimal { self.amount }
+    pub fn success(&self) -> bool { self.success }
+    pub fn timestamp(&self) -> DateTime<Utc> { self.timestamp }
+    pub fn dispute_id(&self) -> Option<DisputeId> { self.dispute_id }
+    pub fn reason(&self) -> Option<ReasonCode> { self.reason }
+    pub fn batch(&self) -> Option<BatchId> { self.batch }
 
-    // TODO: this is used by the tests and would most definitely be used in a larger codebase
-    #[allow(dead_code)]
+    /// Compact record of the transaction to keep in storage
+    pub fn record(&self) -> TransactionRecord {
+        let state = if self.success { TransactionState::Processed } else { TransactionState::Failed };
+        TransactionRecord {
+            kind: self.kind, client_id: self.client_id, amount: self.amount, state, timestamp: self.timestamp,
+            dispute: None, charged_back: Decimal::ZERO, reversal: None,
+        }
+    }
+
+    /// Create a transaction which hasn't been executed yet
     pub fn new(kind: TransactionKind, client_id: ClientId, transaction_id: TransactionId, amount: Decimal) -> Transaction {
-        Transaction{ kind, client_id, transaction_id, amount, success: true }
+        Transaction{
+            kind, client_id, transaction_id, amount, success: true, timestamp: Utc::now(), dispute_id: None, reason: None,
+            batch: None,
+        }
+    }
+
+    /// Set the dispute opened or ended by the transaction
+    pub fn with_dispute(mut self, dispute_id: DisputeId) -> Transaction {
+        self.dispute_id = Some(dispute_id);
+        self
+    }
+
+    /// Set the reason of the dispute opened or charged back by the transaction
+    pub fn with_reason(mut self, reason: ReasonCode) -> Transaction {
+        self.reason = Some(reason);
+        self
+    }
+
+    /// Put the transaction in a batch applied together or not at all
+    pub fn with_batch(mut self, batch: BatchId) -> Transaction {
+        self.batch = Some(batch);
+        self
+    }
+
+    /// Set the time the transaction happened at
+    pub fn with_timestamp(mut self, timestamp: DateTime<Utc>) -> Transaction {
+        self.timestamp = timestamp;
+        self
     }
 
-    /// Carry out the execution of the transaction based on it's kind
-    pub fn exec(&mut self, store: &mut Store) {
-        match self.kind {
+    /// Carry out the execution of the transaction based on it's kind with the default rules
+    pub fn exec(&mut self, store: &mut Store) -> Result<(), TransactionError> {
+        self.exec_with(store, &Rules::default())
+    }
+
+    /// Carry out the execution of the transaction based on it's kind, reporting why it was ignored if it failed
+    pub fn exec_with(&mut self, store: &mut Store, rules: &Rules) -> Result<(), TransactionError> {
+        let span = tracing::info_span!("exec", tx = %self.transaction_id, client = %self.client_id, kind = self.kind.name(),
+                outcome = tracing::field::Empty).entered();
+        store.tick();
+        let result = match self.kind {
+            _ if !store.accepts_client(self.client_id) => Err(TransactionError::UnknownClient),
             TransactionKind::Deposit => self.deposit(store),
             TransactionKind::Withdrawal => self.withdraw(store),
-            TransactionKind::Dispute => self.dispute(store),
+            TransactionKind::Dispute => self.dispute(store, rules),
             TransactionKind::Resolve => self.resolve(store),
-            TransactionKind::Chargeback => self.chargeback(store),
+            TransactionKind::Chargeback => self.chargeback(store, rules),
+            TransactionKind::CreditLimit => self.set_credit_limit(store),
+            TransactionKind::Representment | TransactionKind::ChargebackReversal => self.reverse_chargeback(store, rules),
+            TransactionKind::Reversal => self.reverse(store),
+        };
+        self.success = result.is_ok();
+        match result {
+            Ok(()) => {
+                span.record("outcome", "applied");
+                tracing::debug!("transaction applied");
+            },
+            Err(err) => {
+                span.record("outcome", err.reason());
+                tracing::info!(reason = err.reason(), "transaction ignored");
+            },
         }
+        result
     }
 
     /// Add funds into the client's account
-    fn deposit(&mut self, store: &mut Store) {
+    fn deposit(&mut self, store: &mut Store) -> Result<(), TransactionError> {
+        if store.contains_transaction(self.id()) { return Err(TransactionError::DuplicateTransaction); }
         let client = store.get_or_create_client(self.client_id);
-        if client.locked() { return ;}
+        if client.locked() { return Err(TransactionError::AccountLocked); }
         client.deposit(self.amount);
         self.success = true;
-        store.save_transaction(self.clone());
+        store.save_transaction(self);
+        Ok(())
     }
 
     /// Move funds out of the client's account
-    fn withdraw(&mut self, store: &mut Store) {
+    fn withdraw(&mut self, store: &mut Store) -> Result<(), TransactionError> {
+        if store.contains_transaction(self.id()) { return Err(TransactionError::DuplicateTransaction); }
         let client = store.get_or_create_client(self.client_id);
-        if client.locked() { return ;}
+        if client.locked() { return Err(TransactionError::AccountLocked); }
         self.success = client.withdraw(self.amount);
-        store.save_transaction(self.clone());
+        store.save_transaction(self);
+        if self.success { Ok(()) } else { Err(TransactionError::InsufficientFunds) }
     }
 
-    /// Set up a dispute on a client's account
-    fn dispute(&mut self, store: &mut Store) {
-        let disputed_transaction = store.get_transaction(self.id()).cloned();
+    /// Set up a dispute on a client's account over the amount of the row, or all of what can still be disputed if
+    /// it's zero
+    fn dispute(&mut self, store: &mut Store, rules: &Rules) -> Result<(), TransactionError> {
+        if self.dispute_id.is_some_and(|id| store.get_dispute(id).is_some()) {
+            return Err(TransactionError::DuplicateDispute);
+        }
+        let disputed_transaction = store.get_transaction(self.id()).copied();
         let client = store.get_or_create_client(self.client_id); 
-        if client.locked() { return ;}
-        match disputed_transaction {
-            None => return ,
-            Some(transaction) => {
-                if !client.dispute(transaction.amount) {
-                    // TODO: Handle dispute being over an amount greater than is present on client's account
-                    todo!();
-                }
+        if client.locked() { return Err(TransactionError::AccountLocked); }
+        let mut transaction = disputed_transaction?;
+        // Only the client the transaction belongs to can dispute it
+        if transaction.client_id() != self.client_id { return Err(TransactionError::UnknownTransaction); }
+        let withdrawal = transaction.kind() == TransactionKind::Withdrawal;
+        if transaction.state() == TransactionState::Failed
+            || (withdrawal && rules.withdrawal_disputes == WithdrawalDisputes::Rejected) {
+            return Err(TransactionError::NotDisputable);
+        }
+        if transaction.state() == TransactionState::Reversed { return Err(TransactionError::AlreadyReversed); }
+        let remaining = transaction.disputable_amount();
+        if !transaction.state().disputable() || remaining <= Decimal::ZERO { return Err(TransactionError::AlreadyDisputed); }
+        let amount = if self.amount == Decimal::ZERO { remaining } else { self.amount };
+        if amount < Decimal::ZERO || amount > remaining { return Err(TransactionError::InvalidAmount); }
+        if rules.dispute_window(self.reason).is_some_and(|window| self.timestamp - transaction.timestamp() > window) {
+            return Err(TransactionError::DisputeExpired);
+        }
+
+        let mut shortfall = Decimal::ZERO;
+        if withdrawal {
+            client.recredit(amount);
+        } else if !client.dispute(amount) {
+            // The deposited funds were spent since
+            match rules.spent_disputes {
+                SpentDisputes::Reject => return Err(TransactionError::InsufficientFunds),
+                SpentDisputes::AllowNegative => client.dispute_overdrawing(amount),
+                SpentDisputes::HoldRemaining => shortfall = client.dispute_remaining(amount),
             }
         }
+        let id = self.dispute_id.unwrap_or_else(|| store.next_dispute_id());
+        self.dispute_id = Some(id);
+        store.save_dispute(Dispute::new(id, self.id(), self.client_id, amount, self.timestamp)
+            .with_shortfall(shortfall).with_reason(self.reason));
+        transaction.open_dispute(id);
+        store.update_transaction(self.id(), transaction);
+        Ok(())
+    }
+
+    /// Dispute named on the row if it holds the funds the client's account was opened with, which has no transaction
+    fn opening_dispute(&self, store: &Store) -> Option<Dispute> {
+        let id = store.get_opening(self.client_id)?.dispute()?;
+        if self.dispute_id != Some(id) { return None; }
+        store.get_dispute(id).copied()
+    }
+
+    /// Find the open dispute a resolve or chargeback ends, the one named on the row or else the one over its
+    /// transaction, along with the transaction unless the dispute is over an opening balance
+    fn open_dispute(&mut self, store: &Store) -> Result<(Dispute, Option<TransactionRecord>), TransactionError> {
+        if let Some(dispute) = self.opening_dispute(store) {
+            if dispute.status() != DisputeStatus::Open { return Err(TransactionError::NotDisputed); }
+            return Ok((dispute, None));
+        }
+        let transaction = *store.get_transaction(self.id())?;
+        // Only the client the transaction belongs to can end disputes over it
+        if transaction.client_id() != self.client_id { return Err(TransactionError::UnknownTransaction); }
+        let dispute = match self.dispute_id {
+            Some(id) => store.get_dispute(id).filter(|dispute| dispute.tx() == Some(self.id()))
+                .ok_or(TransactionError::UnknownDispute)?,
+            None => transaction.dispute().and_then(|id| store.get_dispute(id)).ok_or(TransactionError::NotDisputed)?,
+        };
+        if dispute.client_id() != self.client_id { return Err(TransactionError::UnknownDispute); }
+        if dispute.status() != DisputeStatus::Open { return Err(TransactionError::NotDisputed); }
+        self.dispute_id = Some(dispute.id());
+        Ok((*dispute, Some(transaction)))
     }
 
     /// Resolve a dispute on a client's account
-    fn resolve(&mut self, store: &mut Store) {
-        let disputed_transaction = store.get_transaction(self.id()).cloned();
-        let client = store.get_or_create_client(self.client_id); 
-        if client.locked() { return ;}
-        match disputed_transaction {
-            None => return ,
-            Some(transaction) => {
-                if !client.resolve(transaction.amount) {
-                    // TODO: Handle resolution where there isn't enough funds in held to transfer to available
-                    todo!();
-                }
-            }
+    fn resolve(&mut self, store: &mut Store) -> Result<(), TransactionError> {
+        if store.get_or_create_client(self.client_id).locked() { return Err(TransactionError::AccountLocked); }
+        let (mut dispute, transaction) = self.open_dispute(store)?;
+        let client = store.get_or_create_client(self.client_id);
+        let resolved = match transaction.map(|transaction| transaction.kind()) {
+            Some(TransactionKind::Withdrawal) => client.revoke_recredit(dispute.held()),
+            _ => client.resolve(dispute.held()),
+        };
+        // Held funds short of what the dispute holds leave the dispute open and the account as it was
+        if !resolved { return Err(TransactionError::InsufficientHeldFunds); }
+        // Nothing is owed once the client wins the dispute
+        client.settle_shortfall(dispute.shortfall());
+        dispute.close(DisputeStatus::Resolved, self.timestamp);
+        store.save_dispute(dispute);
+        if let Some(mut transaction) = transaction {
+            transaction.resolve();
+            store.update_transaction(self.id(), transaction);
         }
+        Ok(())
     }
     
-    /// Charge back disputed funds from a user's account
-    fn chargeback(&mut self, store: &mut Store) {
-        let disputed_transaction = store.get_transaction(self.id()).cloned();
-        let client = store.get_or_create_client(self.client_id); 
-        if client.locked() { return ;}
-        match disputed_transaction {
-            None => return ,
-            Some(transaction) => {
-                if !client.chargeback(transaction.amount) {
-                    // TODO: Handle when charge back can't take place due to insufficient held funds
-                    todo!();
-                }
-            }
+    /// Charge back disputed funds from a user's account, locking it unless the rules for the dispute's reason say
+    /// otherwise
+    fn chargeback(&mut self, store: &mut Store, rules: &Rules) -> Result<(), TransactionError> {
+        if store.get_or_create_client(self.client_id).locked() { return Err(TransactionError::AccountLocked); }
+        let (mut dispute, transaction) = self.open_dispute(store)?;
+        // The reason given with the chargeback takes over from the one the dispute was raised with
+        if self.reason.is_some() { dispute = dispute.with_reason(self.reason); }
+        let client = store.get_or_create_client(self.client_id);
+        let charged_back = match transaction.map(|transaction| transaction.kind()) {
+            Some(TransactionKind::Withdrawal) => client.chargeback_recredit(dispute.held()),
+            _ => client.chargeback(dispute.held()),
+        };
+        // Held funds short of what the dispute holds leave the dispute open and the account unlocked
+        if !charged_back { return Err(TransactionError::InsufficientHeldFunds); }
+        if rules.for_reason(dispute.reason()).locks { client.lock_for(dispute.id()); }
+        dispute.close(DisputeStatus::ChargedBack, self.timestamp);
+        store.save_dispute(dispute);
+        if let Some(mut transaction) = transaction {
+            transaction.charge_back(dispute.amount());
+            store.update_transaction(self.id(), transaction);
+        }
+        Ok(())
+    }
+
+    /// Give back the funds of a charged back dispute, named on the row or else the latest over its transaction, which
+    /// applies to locked accounts too and unlocks them if the rules allow and it was the only chargeback locking them
+    fn reverse_chargeback(&mut self, store: &mut Store, rules: &Rules) -> Result<(), TransactionError> {
+        let (mut dispute, transaction) = match self.opening_dispute(store) {
+            Some(dispute) => (dispute, None),
+            None => {
+                let transaction = *store.get_transaction(self.id())?;
+                // Only the client the transaction belongs to can have its chargebacks reversed
+                if transaction.client_id() != self.client_id { return Err(TransactionError::UnknownTransaction); }
+                let dispute = match self.dispute_id {
+                    Some(id) => *store.get_dispute(id).filter(|dispute| dispute.tx() == Some(self.id()))
+                        .ok_or(TransactionError::UnknownDispute)?,
+                    None => *transaction.dispute().and_then(|id| store.get_dispute(id))
+                        .ok_or(TransactionError::NotChargedBack)?,
+                };
+                (dispute, Some(transaction))
+            },
+        };
+        if dispute.client_id() != self.client_id { return Err(TransactionError::UnknownTransaction); }
+        if dispute.status() != DisputeStatus::ChargedBack { return Err(TransactionError::NotChargedBack); }
+        self.dispute_id = Some(dispute.id());
+
+        let withdrawal = transaction.is_some_and(|transaction| transaction.kind() == TransactionKind::Withdrawal);
+        let client = store.get_or_create_client(self.client_id);
+        client.reverse_chargeback(dispute.held(), withdrawal);
+        // The merchant keeps the funds, so nothing spent is owed any more
+        client.settle_shortfall(dispute.shortfall());
+        client.release_lock(dispute.id(), rules.reversal_unlocks);
+        dispute.reverse(self.timestamp);
+        store.save_dispute(dispute);
+        if let Some(mut transaction) = transaction {
+            transaction.reverse_chargeback(dispute.amount());
+            store.update_transaction(self.id(), transaction);
+        }
+        Ok(())
+    }
+
+    /// Undo the balance effect of a deposit or withdrawal, which applies to locked accounts too as it corrects an
+    /// error, taking the amount of the reversed transaction if the row leaves it out
+    fn reverse(&mut self, store: &mut Store) -> Result<(), TransactionError> {
+        let mut transaction = *store.get_transaction(self.id())?;
+        // Only the client the transaction belongs to can have it reversed
+        if transaction.client_id() != self.client_id { return Err(TransactionError::UnknownTransaction); }
+        match transaction.state() {
+            TransactionState::Failed => return Err(TransactionError::NotReversible),
+            TransactionState::Reversed => return Err(TransactionError::AlreadyReversed),
+            TransactionState::Disputed | TransactionState::ChargedBack => return Err(TransactionError::AlreadyDisputed),
+            _ if transaction.charged_back() > Decimal::ZERO => return Err(TransactionError::AlreadyDisputed),
+            _ => {},
         }
+        if self.amount != Decimal::ZERO && self.amount != transaction.amount() {
+            return Err(TransactionError::InvalidAmount);
+        }
+
+        let client = store.get_or_create_client(self.client_id);
+        match transaction.kind() {
+            TransactionKind::Withdrawal => client.deposit(transaction.amount()),
+            _ => if !client.reverse_deposit(transaction.amount()) { return Err(TransactionError::InsufficientFunds); },
+        }
+        self.amount = transaction.amount();
+        let reversal = store.next_reversal_id();
+        store.save_reversal(Reversal::new(reversal, self.id(), self.client_id, self.amount, self.timestamp));
+        transaction.reverse(reversal);
+        store.update_transaction(self.id(), transaction);
+        Ok(())
     }
 
+    /// Change how far a client's account can be overdrawn, applies to locked accounts too
+    fn set_credit_limit(&mut self, store: &mut Store) -> Result<(), TransactionError> {
+        if self.amount < Decimal::ZERO { return Err(TransactionError::InvalidAmount); }
+        store.get_or_create_client(self.client_id).set_credit_limit(self.amount);
+        Ok(())
+    }
 }
 
 
@@ -128,33 +801,42 @@ impl Transaction {
 mod tests {
     use std::str::FromStr;
 
+    use chrono::{TimeZone, Utc};
     use rust_decimal::{Decimal, prelude::FromPrimitive};
 
-    use crate::store::Store;
+    use crate::{
+        client::{ClientId, ClientProfile, ClientStatus}, config::{ReasonRules, Rules, SpentDisputes, WithdrawalDisputes},
+        id::num_id, store::Store,
+    };
 
-    use super::{Transaction, TransactionKind};
+    use crate::dispute::{DisputeId, DisputeStatus, ReasonCode};
+
+    use super::{Transaction, TransactionError, TransactionId, TransactionKind, TransactionState};
 
     #[test]
     fn new() {
+        let new_transaction = Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap());
         let test_transaction = Transaction { 
-            kind: TransactionKind::Deposit, client_id: 1, transaction_id: 1, 
-            amount: Decimal::from_u32(100).unwrap(), success: true };
-        assert_eq!(test_transaction, Transaction::new(TransactionKind::Deposit, 1, 1, Decimal::from_u32(100).unwrap()));
+            kind: TransactionKind::Deposit, client_id: num_id(1), transaction_id: num_id(1), 
+            amount: Decimal::from_u32(100).unwrap(), success: true, timestamp: new_transaction.timestamp,
+            dispute_id: None, reason: None, batch: None };
+        assert_eq!(test_transaction, new_transaction);
     }
 
     #[test]
     fn deposit() {
         let mut store = Store::new();
         
-        let test_id = 1;
+        let test_id = num_id(1);
         let mut deposit_transaction = Transaction {
-            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_str("100").unwrap(), success: true};
+            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_str("100").unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-        deposit_transaction.exec(&mut store);
+        deposit_transaction.exec(&mut store).unwrap();
         
         let client = store.get_or_create_client(test_id);
-        assert_eq!(client.id(), 1);
+        assert_eq!(client.id(), test_id);
         assert_eq!(client.available(), Decimal::from_u32(100).unwrap());
         assert_eq!(client.total(), Decimal::from_u32(100).unwrap());
     }
@@ -163,23 +845,25 @@ mod tests {
     fn withdraw() {
         let mut store = Store::new();
         
-        let test_id = 1;
+        let test_id = num_id(1);
         // Deposit 100 onto account
         let mut deposit_transaction = Transaction {
-            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_str("100").unwrap(), success: true};
+            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_str("100").unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-        deposit_transaction.exec(&mut store);
+        deposit_transaction.exec(&mut store).unwrap();
 
         // Withdraw 25 from the account
         let mut withdrawal_transaction = Transaction {
-            kind: TransactionKind::Withdrawal, client_id: test_id, transaction_id: 2, 
-            amount: Decimal::from_str("25").unwrap(), success: true};
+            kind: TransactionKind::Withdrawal, client_id: test_id, transaction_id: num_id(2), 
+            amount: Decimal::from_str("25").unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-        withdrawal_transaction.exec(&mut store);
+        withdrawal_transaction.exec(&mut store).unwrap();
         
         let client = store.get_or_create_client(test_id);
-        assert_eq!(client.id(), 1);
+        assert_eq!(client.id(), test_id);
         assert_eq!(client.available(), Decimal::from_u32(75).unwrap());
         assert_eq!(client.total(), Decimal::from_u32(75).unwrap());
     }
@@ -189,31 +873,34 @@ mod tests {
     fn dispute() {
         let mut store = Store::new();
         
-        let test_id = 1;
+        let test_id = num_id(1);
 
         // Add 100 onto account
         let mut deposit_transaction = Transaction {
-            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_str("100").unwrap(), success: true};
+            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_str("100").unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-        deposit_transaction.exec(&mut store);
+        deposit_transaction.exec(&mut store).unwrap();
        
         // Add 50 onto account
         let mut deposit_transaction2 = Transaction {
-            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: 2, 
-            amount: Decimal::from_str("50").unwrap(), success: true};
+            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: num_id(2), 
+            amount: Decimal::from_str("50").unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-            deposit_transaction2.exec(&mut store);
+            deposit_transaction2.exec(&mut store).unwrap();
 
         // Dispute the 100 deposit
         let mut dispute_transaction = Transaction {
-            kind: TransactionKind::Dispute, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_u32(0).unwrap(), success: true};
+            kind: TransactionKind::Dispute, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_u32(0).unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-        dispute_transaction.exec(&mut store);
+        dispute_transaction.exec(&mut store).unwrap();
         
         let client = store.get_or_create_client(test_id);
-        assert_eq!(client.id(), 1);
+        assert_eq!(client.id(), test_id);
         assert_eq!(client.available(), Decimal::from_u32(50).unwrap());
         assert_eq!(client.held(), Decimal::from_u32(100).unwrap());
         assert_eq!(client.total(), Decimal::from_u32(150).unwrap());
@@ -223,38 +910,42 @@ mod tests {
     fn resolve() {
         let mut store = Store::new();
         
-        let test_id = 1;
+        let test_id = num_id(1);
 
         // Add 100 onto account
         let mut deposit_transaction = Transaction {
-            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_str("100").unwrap(), success: true};
+            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_str("100").unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-        deposit_transaction.exec(&mut store);
+        deposit_transaction.exec(&mut store).unwrap();
        
         // Add 50 onto account
         let mut deposit_transaction2 = Transaction {
-            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: 2, 
-            amount: Decimal::from_str("50").unwrap(), success: true};
+            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: num_id(2), 
+            amount: Decimal::from_str("50").unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-            deposit_transaction2.exec(&mut store);
+            deposit_transaction2.exec(&mut store).unwrap();
 
         // Dispute the 100 deposit
         let mut dispute_transaction = Transaction {
-            kind: TransactionKind::Dispute, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_u32(0).unwrap(), success: true};
+            kind: TransactionKind::Dispute, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_u32(0).unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
        
-        dispute_transaction.exec(&mut store);
+        dispute_transaction.exec(&mut store).unwrap();
        
         // Resolve the 100 deposit dispute
         let mut resolve_transaction = Transaction {
-            kind: TransactionKind::Resolve, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_u32(0).unwrap(), success: true};
+            kind: TransactionKind::Resolve, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_u32(0).unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-        resolve_transaction.exec(&mut store);
+        resolve_transaction.exec(&mut store).unwrap();
         
         let client = store.get_or_create_client(test_id);
-        assert_eq!(client.id(), 1);
+        assert_eq!(client.id(), test_id);
         assert_eq!(client.available(), Decimal::from_u32(150).unwrap());
         assert_eq!(client.held(), Decimal::from_u32(0).unwrap());
         assert_eq!(client.total(), Decimal::from_u32(150).unwrap());
@@ -264,43 +955,615 @@ mod tests {
     fn chargeback() {
         let mut store = Store::new();
         
-        let test_id = 1;
+        let test_id = num_id(1);
 
         // Add 100 onto account
         let mut deposit_transaction = Transaction {
-            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_str("100").unwrap(), success: true};
+            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_str("100").unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-        deposit_transaction.exec(&mut store);
+        deposit_transaction.exec(&mut store).unwrap();
        
         // Add 50 onto account
         let mut deposit_transaction2 = Transaction {
-            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: 2, 
-            amount: Decimal::from_str("50").unwrap(), success: true};
+            kind: TransactionKind::Deposit, client_id: test_id, transaction_id: num_id(2), 
+            amount: Decimal::from_str("50").unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-            deposit_transaction2.exec(&mut store);
+            deposit_transaction2.exec(&mut store).unwrap();
 
         // Dispute the 100 deposit
         let mut dispute_transaction = Transaction {
-            kind: TransactionKind::Dispute, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_u32(0).unwrap(), success: true};
+            kind: TransactionKind::Dispute, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_u32(0).unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
        
-        dispute_transaction.exec(&mut store);
+        dispute_transaction.exec(&mut store).unwrap();
        
         // Charge back the 100 deposit dispute
         let mut chargeback_transaction = Transaction {
-            kind: TransactionKind::Chargeback, client_id: test_id, transaction_id: 1, 
-            amount: Decimal::from_u32(0).unwrap(), success: true};
+            kind: TransactionKind::Chargeback, client_id: test_id, transaction_id: num_id(1), 
+            amount: Decimal::from_u32(0).unwrap(), success: true, timestamp: Utc::now(),
+            dispute_id: None, reason: None, batch: None };
 
-        chargeback_transaction.exec(&mut store);
+        chargeback_transaction.exec(&mut store).unwrap();
         
         let client = store.get_or_create_client(test_id);
-        assert_eq!(client.id(), 1);
+        assert_eq!(client.id(), test_id);
         assert_eq!(client.available(), Decimal::from_u32(50).unwrap());
         assert_eq!(client.held(), Decimal::from_u32(0).unwrap());
         assert_eq!(client.total(), Decimal::from_u32(50).unwrap());
-        assert_eq!(client.locked(), true);
+        assert!(client.locked());
+    }
+
+    #[test]
+    fn insufficient_held_funds() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO).exec(&mut store).unwrap();
+        // Held funds taken outside of the dispute
+        store.get_or_create_client(num_id(1)).chargeback(Decimal::from(60));
+
+        for kind in [TransactionKind::Resolve, TransactionKind::Chargeback] {
+            let mut transaction = Transaction::new(kind, num_id(1), num_id(1), Decimal::ZERO);
+            assert_eq!(transaction.exec(&mut store), Err(TransactionError::InsufficientHeldFunds));
+        }
+        assert_eq!(store.get_dispute(0).unwrap().status(), DisputeStatus::Open);
+        assert_eq!(store.get_transaction(num_id(1)).unwrap().state(), TransactionState::Disputed);
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held(), client.locked()), (Decimal::ZERO, Decimal::from(40), false));
+    }
+
+    #[test]
+    fn failure_reasons() {
+        let mut store = Store::new();
+
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+
+        let mut duplicate = Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap());
+        assert_eq!(duplicate.exec(&mut store), Err(TransactionError::DuplicateTransaction));
+        assert!(!duplicate.success());
+
+        let mut withdrawal = Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(2), Decimal::from_u32(500).unwrap());
+        assert_eq!(withdrawal.exec(&mut store), Err(TransactionError::InsufficientFunds));
+
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(3), Decimal::from_u32(0).unwrap());
+        assert_eq!(dispute.exec(&mut store), Err(TransactionError::UnknownTransaction));
+
+        let mut resolve = Transaction::new(TransactionKind::Resolve, num_id(1), num_id(1), Decimal::from_u32(0).unwrap());
+        assert_eq!(resolve.exec(&mut store), Err(TransactionError::NotDisputed));
+
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(0).unwrap()).exec(&mut store).unwrap();
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(0).unwrap());
+        assert_eq!(dispute.exec(&mut store), Err(TransactionError::AlreadyDisputed));
+
+        let client = store.get_or_create_client(num_id(1));
+        assert_eq!(client.available(), Decimal::from_u32(0).unwrap());
+        assert_eq!(client.held(), Decimal::from_u32(100).unwrap());
+    }
+
+    #[test]
+    fn withdrawal_disputes() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(2), Decimal::from_u32(40).unwrap())
+            .exec(&mut store).unwrap();
+        let mut failed = Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(3), Decimal::from_u32(500).unwrap());
+        assert_eq!(failed.exec(&mut store), Err(TransactionError::InsufficientFunds));
+
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(2), Decimal::from_u32(0).unwrap());
+        assert_eq!(dispute.exec(&mut store), Err(TransactionError::NotDisputable));
+
+        let rules = Rules { withdrawal_disputes: WithdrawalDisputes::Recredit, ..Rules::default() };
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(3), Decimal::from_u32(0).unwrap());
+        assert_eq!(dispute.exec_with(&mut store, &rules), Err(TransactionError::NotDisputable));
+
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(2), Decimal::from_u32(0).unwrap())
+            .exec_with(&mut store, &rules).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(60), Decimal::from(40)));
+
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(2), Decimal::from_u32(0).unwrap())
+            .exec_with(&mut store, &rules).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(100), Decimal::ZERO));
+        assert!(client.locked());
+    }
+
+    #[test]
+    fn withdrawal_dispute_resolved() {
+        let mut store = Store::new();
+        let rules = Rules { withdrawal_disputes: WithdrawalDisputes::Recredit, ..Rules::default() };
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(2), Decimal::from_u32(40).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(2), Decimal::from_u32(0).unwrap())
+            .exec_with(&mut store, &rules).unwrap();
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(2), Decimal::from_u32(0).unwrap())
+            .exec_with(&mut store, &rules).unwrap();
+
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(60), Decimal::ZERO));
+        assert!(!client.locked());
+    }
+
+    #[test]
+    fn spent_disputes() {
+        let spend = |store: &mut Store| {
+            Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+                .exec(store).unwrap();
+            Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(2), Decimal::from_u32(70).unwrap())
+                .exec(store).unwrap();
+        };
+
+        let mut store = Store::new();
+        spend(&mut store);
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(0).unwrap());
+        assert_eq!(dispute.exec(&mut store), Err(TransactionError::InsufficientFunds));
+
+        let mut store = Store::new();
+        spend(&mut store);
+        let rules = Rules { spent_disputes: SpentDisputes::AllowNegative, ..Rules::default() };
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(0).unwrap())
+            .exec_with(&mut store, &rules).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(-70), Decimal::from(100)));
+
+        let mut store = Store::new();
+        spend(&mut store);
+        let rules = Rules { spent_disputes: SpentDisputes::HoldRemaining, ..Rules::default() };
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(0).unwrap())
+            .exec_with(&mut store, &rules).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held(), client.shortfall()), (Decimal::ZERO, Decimal::from(30), Decimal::from(70)));
+
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(1), Decimal::from_u32(0).unwrap())
+            .exec_with(&mut store, &rules).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held(), client.shortfall()), (Decimal::ZERO, Decimal::ZERO, Decimal::from(70)));
+    }
+
+    #[test]
+    fn shortfall_resolved() {
+        let mut store = Store::new();
+        let rules = Rules { spent_disputes: SpentDisputes::HoldRemaining, ..Rules::default() };
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(2), Decimal::from_u32(70).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(0).unwrap())
+            .exec_with(&mut store, &rules).unwrap();
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(1), Decimal::from_u32(0).unwrap())
+            .exec_with(&mut store, &rules).unwrap();
+
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held(), client.shortfall()), (Decimal::from(30), Decimal::ZERO, Decimal::ZERO));
+        assert_eq!(store.get_dispute(0).unwrap().status(), DisputeStatus::Resolved);
+    }
+
+    #[test]
+    fn partial_disputes() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+
+        let mut too_much = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(101).unwrap());
+        assert_eq!(too_much.exec(&mut store), Err(TransactionError::InvalidAmount));
+
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(30).unwrap())
+            .exec(&mut store).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(70), Decimal::from(30)));
+
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(1), Decimal::from_u32(0).unwrap())
+            .exec(&mut store).unwrap();
+        let record = store.get_transaction(num_id(1)).unwrap();
+        assert_eq!((record.charged_back(), record.disputable_amount()), (Decimal::from(30), Decimal::from(70)));
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(70), Decimal::ZERO));
+    }
+
+    #[test]
+    fn remaining_disputable() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(40).unwrap())
+            .exec(&mut store).unwrap();
+        let mut again = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(10).unwrap());
+        assert_eq!(again.exec(&mut store), Err(TransactionError::AlreadyDisputed));
+
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(1), Decimal::from_u32(0).unwrap()).exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(0).unwrap()).exec(&mut store).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::ZERO, Decimal::from(100)));
+    }
+
+    #[test]
+    fn dispute_ids() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(2), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(40).unwrap()).with_dispute(7)
+            .exec(&mut store).unwrap();
+        let mut duplicate = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(2), Decimal::from_u32(0).unwrap())
+            .with_dispute(7);
+        assert_eq!(duplicate.exec(&mut store), Err(TransactionError::DuplicateDispute));
+        let mut wrong_tx = Transaction::new(TransactionKind::Resolve, num_id(1), num_id(2), Decimal::from_u32(0).unwrap())
+            .with_dispute(7);
+        assert_eq!(wrong_tx.exec(&mut store), Err(TransactionError::UnknownDispute));
+
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(1), Decimal::from_u32(0).unwrap()).with_dispute(7)
+            .exec(&mut store).unwrap();
+        let mut again = Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(1), Decimal::from_u32(0).unwrap())
+            .with_dispute(7);
+        assert_eq!(again.exec(&mut store), Err(TransactionError::NotDisputed));
+
+        // A second dispute over the same transaction gets the next free ID and is ended through the transaction
+        let mut second = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(0).unwrap());
+        second.exec(&mut store).unwrap();
+        assert_eq!(second.dispute_id(), Some(8));
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(1), Decimal::from_u32(0).unwrap())
+            .exec(&mut store).unwrap();
+
+        let statuses: Vec<(DisputeId, Decimal, DisputeStatus)> = [7, 8].iter()
+            .map(|id| store.get_dispute(*id).unwrap())
+            .map(|dispute| (dispute.id(), dispute.amount(), dispute.status()))
+            .collect();
+        assert_eq!(statuses, vec![
+            (7, Decimal::from(40), DisputeStatus::Resolved), (8, Decimal::from(100), DisputeStatus::ChargedBack),
+        ]);
+    }
+
+    #[test]
+    fn other_client_disputes() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Deposit, num_id(2), num_id(2), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(2), num_id(1), Decimal::ZERO);
+        assert_eq!(dispute.exec(&mut store), Err(TransactionError::UnknownTransaction));
+        assert_eq!(store.get_transaction(num_id(1)).unwrap().state(), TransactionState::Processed);
+
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO).with_dispute(7).exec(&mut store).unwrap();
+        for kind in [TransactionKind::Resolve, TransactionKind::Chargeback] {
+            let mut by_transaction = Transaction::new(kind, num_id(2), num_id(1), Decimal::ZERO);
+            assert_eq!(by_transaction.exec(&mut store), Err(TransactionError::UnknownTransaction));
+            let mut by_dispute = Transaction::new(kind, num_id(2), num_id(1), Decimal::ZERO).with_dispute(7);
+            assert_eq!(by_dispute.exec(&mut store), Err(TransactionError::UnknownTransaction));
+        }
+
+        assert_eq!(store.get_dispute(7).unwrap().status(), DisputeStatus::Open);
+        let (owner, other) = (store.get_client(num_id(1)).unwrap(), store.get_client(num_id(2)).unwrap());
+        assert_eq!((owner.available(), owner.held(), owner.locked()), (Decimal::ZERO, Decimal::from(100), false));
+        assert_eq!((other.available(), other.held(), other.locked()), (Decimal::from(100), Decimal::ZERO, false));
+    }
+
+    #[test]
+    fn parse_optional_amount() {
+        let rows = "type,client,tx,amount,dispute\ndispute,1,1,,\ndispute,1,2,2.5,4\n";
+        let transactions: Vec<Transaction> = csv::Reader::from_reader(rows.as_bytes())
+            .deserialize().collect::<Result<_, _>>().unwrap();
+        assert_eq!((transactions[0].amount(), transactions[0].dispute_id()), (Decimal::ZERO, None));
+        assert_eq!((transactions[1].amount(), transactions[1].dispute_id()), (Decimal::from_str("2.5").unwrap(), Some(4)));
+
+        let row = serde_json::json!({ "type": "resolve", "client": num_id::<ClientId>(1), "tx": num_id::<TransactionId>(1) });
+        let transaction: Transaction = serde_json::from_value(row).unwrap();
+        assert_eq!(transaction.amount(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn parse_timestamps() {
+        let rows = "type,client,tx,amount,timestamp\ndeposit,1,1,10,2022-09-01T12:00:00Z\ndeposit,1,2,10,1662033600\ndeposit,1,3,10,\n";
+        let before = Utc::now();
+        let transactions: Vec<Transaction> = csv::Reader::from_reader(rows.as_bytes())
+            .deserialize().collect::<Result<_, _>>().unwrap();
+
+        let expected = Utc.with_ymd_and_hms(2022, 9, 1, 12, 0, 0).unwrap();
+        assert_eq!(transactions[0].timestamp(), expected);
+        assert_eq!(transactions[1].timestamp(), expected);
+        assert!(transactions[2].timestamp() >= before);
+
+        // Column missing altogether
+        let transaction: Transaction = csv::Reader::from_reader("type,client,tx,amount\ndeposit,1,1,10\n".as_bytes())
+            .deserialize().next().unwrap().unwrap();
+        assert!(transaction.timestamp() >= before);
+    }
+
+    #[test]
+    fn success_not_read() {
+        let rows = "type,client,tx,amount,success\nwithdrawal,1,1,10,true\n";
+        let transaction: Transaction = csv::Reader::from_reader(rows.as_bytes()).deserialize().next().unwrap().unwrap();
+        assert!(!transaction.success());
+        let row = serde_json::json!({
+            "type": "deposit", "client": num_id::<ClientId>(1), "tx": num_id::<TransactionId>(1), "amount": "10", "success": true,
+        });
+        let transaction: Transaction = serde_json::from_value(row).unwrap();
+        assert!(!transaction.success());
     }
 
+    #[test]
+    fn dispute_window() {
+        let mut store = Store::new();
+        let rules = Rules { dispute_window_days: Some(120), ..Rules::default() };
+        let deposited = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+
+        for id in [1, 2] {
+            Transaction::new(TransactionKind::Deposit, num_id(1), num_id(id), Decimal::from_u32(100).unwrap())
+                .with_timestamp(deposited).exec_with(&mut store, &rules).unwrap();
+        }
+
+        let mut late_dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(0).unwrap())
+            .with_timestamp(Utc.with_ymd_and_hms(2022, 6, 1, 0, 0, 0).unwrap());
+        assert_eq!(late_dispute.exec_with(&mut store, &rules), Err(TransactionError::DisputeExpired));
 
-}
\ No newline at end of file
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(2), Decimal::from_u32(0).unwrap())
+            .with_timestamp(Utc.with_ymd_and_hms(2022, 4, 1, 0, 0, 0).unwrap());
+        dispute.exec_with(&mut store, &rules).unwrap();
+        assert_eq!(store.get_or_create_client(num_id(1)).held(), Decimal::from_u32(100).unwrap());
+    }
+
+    #[test]
+    fn credit_limit() {
+        let mut store = Store::new();
+        let mut withdrawal = Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(1), Decimal::from_u32(100).unwrap());
+        assert_eq!(withdrawal.exec(&mut store), Err(TransactionError::InsufficientFunds));
+
+        let mut negative = Transaction::new(TransactionKind::CreditLimit, num_id(1), num_id(2), Decimal::from(-100));
+        assert_eq!(negative.exec(&mut store), Err(TransactionError::InvalidAmount));
+        Transaction::new(TransactionKind::CreditLimit, num_id(1), num_id(3), Decimal::from_u32(150).unwrap())
+            .exec(&mut store).unwrap();
+
+        let mut withdrawal = Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(4), Decimal::from_u32(100).unwrap());
+        withdrawal.exec(&mut store).unwrap();
+        assert_eq!(store.get_client(num_id(1)).unwrap().available(), Decimal::from(-100));
+        assert!(store.get_client(num_id(1)).unwrap().overdrawn());
+    }
+
+    #[test]
+    fn reverse_chargeback() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        let mut early = Transaction::new(TransactionKind::Representment, num_id(1), num_id(1), Decimal::ZERO);
+        assert_eq!(early.exec(&mut store), Err(TransactionError::NotChargedBack));
+
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::from_u32(40).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(1), Decimal::ZERO).exec(&mut store).unwrap();
+        assert!(store.get_client(num_id(1)).unwrap().locked());
+
+        let mut unknown = Transaction::new(TransactionKind::Representment, num_id(1), num_id(1), Decimal::ZERO).with_dispute(5);
+        assert_eq!(unknown.exec(&mut store), Err(TransactionError::UnknownDispute));
+        let mut reversal = Transaction::new(TransactionKind::Representment, num_id(1), num_id(1), Decimal::ZERO);
+        reversal.exec(&mut store).unwrap();
+        assert_eq!(reversal.dispute_id(), Some(0));
+
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.held()), (Decimal::from(100), Decimal::ZERO));
+        // Unlocking has to be enabled in the rules
+        assert!(client.locked());
+        let record = store.get_transaction(num_id(1)).unwrap();
+        assert_eq!((record.state(), record.charged_back()), (TransactionState::Resolved, Decimal::ZERO));
+        let dispute = store.get_dispute(0).unwrap();
+        assert_eq!(dispute.status(), DisputeStatus::Reversed);
+        assert_eq!(dispute.reversed_at(), Some(reversal.timestamp()));
+        assert!(dispute.closed_at().is_some());
+
+        let mut again = Transaction::new(TransactionKind::ChargebackReversal, num_id(1), num_id(1), Decimal::ZERO);
+        assert_eq!(again.exec(&mut store), Err(TransactionError::NotChargedBack));
+    }
+
+    #[test]
+    fn other_client_reverse_chargeback() {
+        let mut store = Store::new();
+        let rules = Rules { reversal_unlocks: true, ..Rules::default() };
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Deposit, num_id(2), num_id(2), Decimal::from_u32(10).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO).exec_with(&mut store, &rules).unwrap();
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(1), Decimal::ZERO).exec_with(&mut store, &rules).unwrap();
+
+        for kind in [TransactionKind::Representment, TransactionKind::ChargebackReversal] {
+            let mut by_transaction = Transaction::new(kind, num_id(2), num_id(1), Decimal::ZERO);
+            assert_eq!(by_transaction.exec_with(&mut store, &rules), Err(TransactionError::UnknownTransaction));
+            let mut by_dispute = Transaction::new(kind, num_id(2), num_id(1), Decimal::ZERO).with_dispute(0);
+            assert_eq!(by_dispute.exec_with(&mut store, &rules), Err(TransactionError::UnknownTransaction));
+        }
+
+        assert_eq!(store.get_dispute(0).unwrap().status(), DisputeStatus::ChargedBack);
+        let (owner, other) = (store.get_client(num_id(1)).unwrap(), store.get_client(num_id(2)).unwrap());
+        assert_eq!((owner.available(), owner.locked()), (Decimal::ZERO, true));
+        assert_eq!((other.available(), other.locked()), (Decimal::from(10), false));
+    }
+
+    #[test]
+    fn reversal_unlocks() {
+        let mut store = Store::new();
+        let rules = Rules { reversal_unlocks: true, ..Rules::default() };
+        for tx in [1, 2] {
+            Transaction::new(TransactionKind::Deposit, num_id(1), num_id(tx), Decimal::from_u32(50).unwrap())
+                .exec(&mut store).unwrap();
+            Transaction::new(TransactionKind::Dispute, num_id(1), num_id(tx), Decimal::ZERO)
+                .exec_with(&mut store, &rules).unwrap();
+        }
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(1), Decimal::ZERO).exec_with(&mut store, &rules).unwrap();
+        let mut locked = Transaction::new(TransactionKind::Resolve, num_id(1), num_id(2), Decimal::ZERO);
+        assert_eq!(locked.exec_with(&mut store, &rules), Err(TransactionError::AccountLocked));
+
+        Transaction::new(TransactionKind::ChargebackReversal, num_id(1), num_id(1), Decimal::ZERO)
+            .exec_with(&mut store, &rules).unwrap();
+        assert!(!store.get_client(num_id(1)).unwrap().locked());
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(2), Decimal::ZERO).exec_with(&mut store, &rules).unwrap();
+        assert_eq!(store.get_client(num_id(1)).unwrap().available(), Decimal::from(100));
+    }
+
+    #[test]
+    fn reverse_withdrawal_chargeback() {
+        let mut store = Store::new();
+        let rules = Rules { withdrawal_disputes: WithdrawalDisputes::Recredit, reversal_unlocks: true, ..Rules::default() };
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(2), Decimal::from_u32(30).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(2), Decimal::ZERO).exec_with(&mut store, &rules).unwrap();
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(2), Decimal::ZERO).exec_with(&mut store, &rules).unwrap();
+        assert_eq!(store.get_client(num_id(1)).unwrap().available(), Decimal::from(100));
+
+        Transaction::new(TransactionKind::Representment, num_id(1), num_id(2), Decimal::ZERO)
+            .exec_with(&mut store, &rules).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.locked()), (Decimal::from(70), false));
+    }
+
+    #[test]
+    fn reason_codes() {
+        let mut store = Store::new();
+        let mut rules = Rules::default();
+        rules.reasons.insert(ReasonCode::NotReceived, ReasonRules { locks: false, dispute_window_days: None });
+        for tx in [1, 2] {
+            Transaction::new(TransactionKind::Deposit, num_id(1), num_id(tx), Decimal::from_u32(50).unwrap())
+                .exec(&mut store).unwrap();
+        }
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO).with_reason(ReasonCode::NotReceived)
+            .exec_with(&mut store, &rules).unwrap();
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(1), Decimal::ZERO).exec_with(&mut store, &rules).unwrap();
+        assert_eq!(store.get_dispute(0).unwrap().reason(), Some(ReasonCode::NotReceived));
+        assert!(!store.get_client(num_id(1)).unwrap().locked());
+
+        // A reason given with the chargeback replaces the dispute's
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(2), Decimal::ZERO).with_reason(ReasonCode::NotReceived)
+            .exec_with(&mut store, &rules).unwrap();
+        Transaction::new(TransactionKind::Chargeback, num_id(1), num_id(2), Decimal::ZERO).with_reason(ReasonCode::Fraud)
+            .exec_with(&mut store, &rules).unwrap();
+        assert_eq!(store.get_dispute(1).unwrap().reason(), Some(ReasonCode::Fraud));
+        assert!(store.get_client(num_id(1)).unwrap().locked());
+    }
+
+    #[test]
+    fn parse_reason() {
+        let mut reader = csv::Reader::from_reader("type,client,tx,amount,reason\ndispute,1,1,,fraud\nresolve,1,1,,\n\
+                dispute,1,1,,theft\n".as_bytes());
+        let rows: Vec<Result<Transaction, csv::Error>> = reader.deserialize().collect();
+        assert_eq!(rows[0].as_ref().unwrap().reason(), Some(ReasonCode::Fraud));
+        assert_eq!(rows[1].as_ref().unwrap().reason(), None);
+        assert!(rows[2].is_err());
+    }
+
+    #[test]
+    fn reversal() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(2), Decimal::from_u32(30).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(3), Decimal::from_u32(500).unwrap())
+            .exec(&mut store).unwrap_err();
+
+        let mut wrong_amount = Transaction::new(TransactionKind::Reversal, num_id(1), num_id(2), Decimal::from_u32(20).unwrap());
+        assert_eq!(wrong_amount.exec(&mut store), Err(TransactionError::InvalidAmount));
+        let mut other_client = Transaction::new(TransactionKind::Reversal, num_id(2), num_id(2), Decimal::ZERO);
+        assert_eq!(other_client.exec(&mut store), Err(TransactionError::UnknownTransaction));
+        let mut failed = Transaction::new(TransactionKind::Reversal, num_id(1), num_id(3), Decimal::ZERO);
+        assert_eq!(failed.exec(&mut store), Err(TransactionError::NotReversible));
+
+        let mut reversal = Transaction::new(TransactionKind::Reversal, num_id(1), num_id(2), Decimal::ZERO);
+        reversal.exec(&mut store).unwrap();
+        assert_eq!(reversal.amount(), Decimal::from(30));
+        assert_eq!(store.get_client(num_id(1)).unwrap().available(), Decimal::from(100));
+        let record = store.get_transaction(num_id(2)).unwrap();
+        assert_eq!((record.state(), record.reversal()), (TransactionState::Reversed, Some(0)));
+        let stored = store.get_reversal(0).unwrap();
+        assert_eq!((stored.tx(), stored.client_id(), stored.amount()), (num_id(2), num_id(1), Decimal::from(30)));
+        assert_eq!(stored.timestamp(), reversal.timestamp());
+
+        let mut again = Transaction::new(TransactionKind::Reversal, num_id(1), num_id(2), Decimal::ZERO);
+        assert_eq!(again.exec(&mut store), Err(TransactionError::AlreadyReversed));
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(2), Decimal::ZERO);
+        assert_eq!(dispute.exec(&mut store), Err(TransactionError::NotDisputable));
+
+        Transaction::new(TransactionKind::Reversal, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.locked()), (Decimal::ZERO, false));
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(1), num_id(1), Decimal::ZERO);
+        assert_eq!(dispute.exec(&mut store), Err(TransactionError::AlreadyReversed));
+    }
+
+    #[test]
+    fn reversal_with_credit_limit() {
+        let mut store = Store::new();
+        store.set_credit_limits([(num_id(1), Decimal::from(50))]);
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(30).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Reversal, num_id(1), num_id(1), Decimal::ZERO).exec(&mut store).unwrap();
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.credit_used()), (Decimal::ZERO, Decimal::ZERO));
+        assert!(!client.overdrawn());
+
+        // The deposit was spent on credit, taking it back mustn't draw on the credit limit as well
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(2), Decimal::from_u32(10).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(3), Decimal::from_u32(20).unwrap())
+            .exec(&mut store).unwrap();
+        let mut reversal = Transaction::new(TransactionKind::Reversal, num_id(1), num_id(2), Decimal::ZERO);
+        assert_eq!(reversal.exec(&mut store), Err(TransactionError::InsufficientFunds));
+        let client = store.get_client(num_id(1)).unwrap();
+        assert_eq!((client.available(), client.credit_used()), (Decimal::from(-10), Decimal::from(10)));
+    }
+
+    #[test]
+    fn reversal_of_disputed() {
+        let mut store = Store::new();
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(100).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Withdrawal, num_id(1), num_id(2), Decimal::from_u32(80).unwrap())
+            .exec(&mut store).unwrap();
+        let mut spent = Transaction::new(TransactionKind::Reversal, num_id(1), num_id(1), Decimal::ZERO);
+        assert_eq!(spent.exec(&mut store), Err(TransactionError::InsufficientFunds));
+
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(3), Decimal::from_u32(10).unwrap())
+            .exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Dispute, num_id(1), num_id(3), Decimal::ZERO).exec(&mut store).unwrap();
+        let mut disputed = Transaction::new(TransactionKind::Reversal, num_id(1), num_id(3), Decimal::ZERO);
+        assert_eq!(disputed.exec(&mut store), Err(TransactionError::AlreadyDisputed));
+        Transaction::new(TransactionKind::Resolve, num_id(1), num_id(3), Decimal::ZERO).exec(&mut store).unwrap();
+        Transaction::new(TransactionKind::Reversal, num_id(1), num_id(3), Decimal::ZERO).exec(&mut store).unwrap();
+        assert_eq!(store.get_client(num_id(1)).unwrap().available(), Decimal::from(20));
+    }
+
+    #[test]
+    fn strict_accounts() {
+        let mut store = Store::new();
+        let profile = ClientProfile { name: "Ada".to_string(), status: ClientStatus::Active, kyc_tier: 2 };
+        store.set_profiles([(num_id(1), profile)], true);
+        Transaction::new(TransactionKind::Deposit, num_id(1), num_id(1), Decimal::from_u32(10).unwrap())
+            .exec(&mut store).unwrap();
+
+        let mut unknown = Transaction::new(TransactionKind::Deposit, num_id(2), num_id(2), Decimal::from_u32(10).unwrap());
+        assert_eq!(unknown.exec(&mut store), Err(TransactionError::UnknownClient));
+        let mut dispute = Transaction::new(TransactionKind::Dispute, num_id(2), num_id(1), Decimal::ZERO);
+        assert_eq!(dispute.exec(&mut store), Err(TransactionError::UnknownClient));
+        assert!(store.get_client(num_id(2)).is_none());
+        assert!(!store.contains_transaction(num_id(2)));
+    }
+
+    #[test]
+    fn parse_credit_limit() {
+        let transaction: Transaction = csv::Reader::from_reader("type,client,tx,amount\ncredit_limit,1,1,500\n".as_bytes())
+            .deserialize().next().unwrap().unwrap();
+        assert_eq!(transaction.kind(), TransactionKind::CreditLimit);
+    }
+
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::{
+    engine::{Engine, Flag, Outcome, Settled}, error::Error, io::{OutputFormat, TransactionReader},
+    transaction::{Transaction, TransactionError, TransactionId},
+};
+
+
+/// Problem found on a single row of the input
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Issue {
+    /// Line of the input the row starts on
+    pub line: Option<u64>,
+    /// Transaction ID of the row, if it could be parsed
+    pub tx: Option<TransactionId>,
+    pub message: String,
+}
+
+/// Limits on the problems found before the input is rejected
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Thresholds {
+    /// Maximum number of parse errors, duplicate IDs and unknown references combined
+    pub max_errors: u64,
+    /// Maximum number of rows which would fail to apply, unlimited if not set
+    pub max_failures: Option<u64>,
+}
+
+/// Result of a dry run of the input against a throwaway store
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ValidationReport {
+    /// Rows read from the input, including those which failed to parse
+    pub rows: u64,
+    /// Rows which would be applied to the accounts
+    pub applied: u64,
+    /// Parsed rows per transaction kind
+    pub kinds: BTreeMap<&'static str, u64>,
+    pub parse_errors: Vec<Issue>,
+    pub duplicate_ids: Vec<Issue>,
+    pub unknown_references: Vec<Issue>,
+    /// Rows a policy flagged for review, they are applied all the same
+    pub flagged: Vec<Issue>,
+    /// Rows which parsed but would be ignored, counted by reason
+    pub failures: BTreeMap<&'static str, u64>,
+}
+
+
+/// Parse every row of the input and execute it against a throwaway engine, collecting every problem found
+pub fn validate(mut reader: TransactionReader, mut engine: Engine) -> Result<ValidationReport, Error> {
+    let mut report = ValidationReport::default();
+    // Lines buffered references arrived on by the transaction they wait for, to report them once settled
+    let mut buffered_lines: HashMap<TransactionId, VecDeque<u64>> = HashMap::new();
+
+    while let Some(row) = reader.next() {
+        report.rows += 1;
+        let transaction = match row {
+            Ok(transaction) => transaction,
+            Err(Error::Parse { line, message, .. }) => {
+                report.parse_errors.push(Issue { line, tx: None, message });
+                continue;
+            },
+            // Failing to read the input at all isn't something to report on
+            Err(err) => return Err(err),
+        };
+
+        *report.kinds.entry(transaction.kind().name()).or_default() += 1;
+        match engine.process(transaction) {
+            Outcome::Executed(result) => report.record(&transaction, Some(reader.line()), result),
+            Outcome::Buffered | Outcome::Batched =>
+                buffered_lines.entry(transaction.id()).or_default().push_back(reader.line()),
+        }
+        report.record_flagged(&mut engine, &transaction, reader.line());
+        report.record_settled(&mut engine, &mut buffered_lines);
+    }
+
+    engine.finish();
+    report.record_settled(&mut engine, &mut buffered_lines);
+    Ok(report)
+}
+
+
+impl ValidationReport {
+    /// Count the result of executing a transaction
+    fn record(&mut self, transaction: &Transaction, line: Option<u64>, result: Result<(), TransactionError>) {
+        let (kind, tx) = (transaction.kind().name(), Some(transaction.id()));
+        match result {
+            Ok(()) => self.applied += 1,
+            Err(TransactionError::DuplicateTransaction) => self.duplicate_ids.push(Issue {
+                line, tx, message: format!("{} reuses transaction ID {}", kind, transaction.id()),
+            }),
+            Err(TransactionError::UnknownTransaction) => self.unknown_references.push(Issue {
+                line, tx, message: format!("{} references unknown transaction {}", kind, transaction.id()),
+            }),
+            Err(TransactionError::Orphaned) => self.unknown_references.push(Issue {
+                line, tx, message: format!("{} waited for transaction {} which never arrived", kind, transaction.id()),
+            }),
+            Err(err) => *self.failures.entry(err.reason()).or_default() += 1,
+        }
+    }
+
+    /// Count the results of buffered transactions the engine settled
+    fn record_settled(&mut self, engine: &mut Engine, buffered_lines: &mut HashMap<TransactionId, VecDeque<u64>>) {
+        for Settled { transaction, result } in engine.take_settled() {
+            let line = buffered_lines.get_mut(&transaction.id()).and_then(VecDeque::pop_front);
+            self.record(&transaction, line, result);
+        }
+        buffered_lines.retain(|_, lines| !lines.is_empty());
+    }
+
+    /// Collect the transactions flagged for review while processing a row, the row's line is only known for its own
+    /// transaction and not for buffered ones it released
+    fn record_flagged(&mut self, engine: &mut Engine, row: &Transaction, line: u64) {
+        for Flag { transaction, reason } in engine.take_flagged() {
+            let own = transaction.id() == row.id() && transaction.kind() == row.kind();
+            self.flagged.push(Issue {
+                line: own.then_some(line), tx: Some(transaction.id()),
+                message: format!("{} for client {} flagged by {}", transaction.kind().name(), transaction.client_id(), reason),
+            });
+        }
+    }
+
+    /// Number of rows which are malformed or reference transactions incorrectly
+    pub fn errors(&self) -> u64 {
+        (self.parse_errors.len() + self.duplicate_ids.len() + self.unknown_references.len()) as u64
+    }
+
+    /// Number of well formed rows which would be ignored
+    pub fn failures(&self) -> u64 {
+        self.failures.values().sum()
+    }
+
+    /// Reject the input if any of the thresholds are exceeded
+    pub fn check(&self, thresholds: Thresholds) -> Result<(), Error> {
+        if self.errors() > thresholds.max_errors {
+            return Err(Error::Rejected(format!(
+                "{} errors exceed the threshold of {}", self.errors(), thresholds.max_errors)));
+        }
+        match thresholds.max_failures {
+            Some(max_failures) if self.failures() > max_failures => Err(Error::Rejected(format!(
+                "{} failed transactions exceed the threshold of {}", self.failures(), max_failures))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the report in the requested format
+    pub fn write(&self, format: OutputFormat, out: &mut dyn Write) -> io::Result<()> {
+        match format {
+            OutputFormat::Csv => self.write_text(out)?,
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut *out, self)?;
+                writeln!(out)?;
+            },
+        }
+        out.flush()
+    }
+
+    /// Write every issue followed by the summary counts
+    fn write_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        let issues = [
+            ("parse error", &self.parse_errors),
+            ("duplicate id", &self.duplicate_ids),
+            ("unknown reference", &self.unknown_references),
+            ("review", &self.flagged),
+        ];
+        for (label, issues) in issues {
+            for issue in issues {
+                match issue.line {
+                    Some(line) => writeln!(out, "line {}: {}: {}", line, label, issue.message)?,
+                    None => writeln!(out, "{}: {}", label, issue.message)?,
+                }
+            }
+        }
+
+        writeln!(out, "{0: <28} {1}", "rows", self.rows)?;
+        writeln!(out, "{0: <28} {1}", "applied", self.applied)?;
+        for (kind, count) in &self.kinds {
+            writeln!(out, "{0: <28} {1}", format!("kind {}", kind), count)?;
+        }
+        writeln!(out, "{0: <28} {1}", "parse errors", self.parse_errors.len())?;
+        writeln!(out, "{0: <28} {1}", "duplicate ids", self.duplicate_ids.len())?;
+        writeln!(out, "{0: <28} {1}", "unknown references", self.unknown_references.len())?;
+        writeln!(out, "{0: <28} {1}", "flagged for review", self.flagged.len())?;
+        for (reason, count) in &self.failures {
+            writeln!(out, "{0: <28} {1}", format!("failed {}", reason), count)?;
+        }
+        Ok(())
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{config::{Config, Rules}, engine::BufferPolicy, id::num_id, policy::build_policies, store::Store};
+
+    use super::*;
+
+    /// Write the rows into a temporary CSV file and validate it
+    fn validate_rows(name: &str, rows: &str) -> ValidationReport {
+        let path = std::env::temp_dir().join(format!("transactions-validate-{}-{}.csv", name, std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, rows).unwrap();
+        let engine = Engine::new(Store::new(), Rules::default(), BufferPolicy { max_rows: Some(2), max_age_seconds: None });
+        let report = validate(TransactionReader::new(path).unwrap(), engine);
+        fs::remove_file(path).unwrap();
+        report.unwrap()
+    }
+
+    #[test]
+    fn clean_input() {
+        let report = validate_rows("clean", "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,5\n");
+        assert_eq!(report.rows, 2);
+        assert_eq!(report.applied, 2);
+        assert_eq!(report.errors(), 0);
+        assert!(report.check(Thresholds::default()).is_ok());
+    }
+
+    #[test]
+    fn problems_with_lines() {
+        let report = validate_rows("problems", concat!(
+            "type,client,tx,amount\n",
+            "deposit,1,1,10\n",
+            "deposit,1,10,x\n",
+            "deposit,1,1,10\n",
+            "dispute,1,9,0\n",
+            "withdrawal,1,2,50\n",
+            "dispute,1,3,0\n",
+            "deposit,1,3,5\n",
+        ));
+        assert_eq!(report.rows, 7);
+        assert_eq!(report.applied, 3);
+        assert_eq!(report.parse_errors[0].line, Some(3));
+        assert_eq!(report.duplicate_ids[0].line, Some(4));
+        assert_eq!(report.duplicate_ids[0].tx, Some(num_id(1)));
+        assert_eq!(report.unknown_references[0].line, Some(5));
+        assert_eq!(report.failures.get("insufficient_funds"), Some(&1));
+        assert_eq!(report.errors(), 3);
+    }
+
+    #[test]
+    fn policy_decisions() {
+        let path = std::env::temp_dir().join(format!("transactions-validate-policy-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "type,client,tx,amount\ndeposit,1,1,500\ndeposit,2,2,50\nwithdrawal,2,3,20\n").unwrap();
+        let config = Config::parse("test.toml", concat!(
+            "[[policies]]\ntype = \"max_amount\"\nmax = 100\n",
+            "[[policies]]\ntype = \"blocked_clients\"\nclients = [2]\naction = \"review\"\n",
+        )).unwrap();
+        let engine = Engine::new(Store::new(), Rules::default(), BufferPolicy::default())
+            .with_policies(build_policies(&config.policies));
+        let report = validate(TransactionReader::new(path).unwrap(), engine);
+        fs::remove_file(path).unwrap();
+
+        let report = report.unwrap();
+        assert_eq!(report.applied, 2);
+        assert_eq!(report.failures.get("max_amount"), Some(&1));
+        assert_eq!(report.flagged.iter().map(|issue| issue.line).collect::<Vec<_>>(), vec![Some(3), Some(4)]);
+        assert_eq!(report.errors(), 0);
+    }
+
+    #[test]
+    fn thresholds() {
+        let report = validate_rows("thresholds", "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,50\ndispute,1,9,0\n");
+        assert!(report.check(Thresholds::default()).is_err());
+        assert!(report.check(Thresholds { max_errors: 1, max_failures: None }).is_ok());
+        assert!(report.check(Thresholds { max_errors: 1, max_failures: Some(0) }).is_err());
+    }
+
+}